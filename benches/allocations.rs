@@ -0,0 +1,79 @@
+//! Counts heap allocations made while streaming 1 MiB through the mock.
+//! Run with `cargo bench --bench allocations`.
+
+use serial_mock::{MockableSerial, SerialMock};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static ALLOC_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const STREAM_LEN: usize = 1024 * 1024;
+const FRAME_LEN: usize = 64;
+
+fn port_with_frames() -> MockableSerial {
+    let mut port = MockableSerial::new("/dev/null", 115200, b'#', FRAME_LEN as u32);
+    let mut frame = vec![b'a'; FRAME_LEN - 1];
+    frame.push(b'#');
+
+    for _ in 0..STREAM_LEN / FRAME_LEN {
+        port.add_response(&frame);
+    }
+
+    port
+}
+
+fn measure(name: &str, f: impl FnOnce(&mut MockableSerial) -> usize) {
+    let mut port = port_with_frames();
+
+    ALLOCS.store(0, Ordering::Relaxed);
+    ALLOC_BYTES.store(0, Ordering::Relaxed);
+    let total = f(&mut port);
+    let allocs = ALLOCS.load(Ordering::Relaxed);
+    let bytes = ALLOC_BYTES.load(Ordering::Relaxed);
+
+    assert_eq!(total, STREAM_LEN);
+    println!("{name:<12} {allocs:>8} allocations {bytes:>10} bytes allocated");
+}
+
+fn main() {
+    measure("read", |port| {
+        let mut buf = [0; FRAME_LEN];
+        let mut total = 0;
+        while let Ok(n) = port.read(&mut buf) {
+            total += n;
+        }
+        total
+    });
+
+    measure("read_frame", |port| {
+        let mut total = 0;
+        while let Ok(frame) = port.read_frame() {
+            total += frame.len();
+        }
+        total
+    });
+}
@@ -0,0 +1,73 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use serial_mock::{MockableSerial, SerialMock};
+
+const STREAM_LEN: usize = 64 * 1024;
+
+fn port_with_frames(frame_len: usize, read_n_bytes: u32) -> MockableSerial {
+    let mut port = MockableSerial::new("/dev/null", 115200, b'#', read_n_bytes);
+    let mut frame = vec![b'a'; frame_len - 1];
+    frame.push(b'#');
+
+    for _ in 0..STREAM_LEN / frame_len {
+        port.add_response(&frame);
+    }
+
+    port
+}
+
+fn drain(port: &mut MockableSerial, buf: &mut [u8]) -> usize {
+    let mut total = 0;
+
+    while let Ok(n) = port.read(buf) {
+        total += n;
+    }
+
+    total
+}
+
+fn bench_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Bytes(STREAM_LEN as u64));
+
+    group.bench_function("single_byte", |b| {
+        b.iter_batched(
+            || port_with_frames(64, 1),
+            |mut port| drain(&mut port, &mut [0; 1]),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("multi_byte", |b| {
+        b.iter_batched(
+            || port_with_frames(64, 64),
+            |mut port| drain(&mut port, &mut [0; 64]),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+fn bench_read_frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_frame");
+    group.throughput(Throughput::Bytes(STREAM_LEN as u64));
+
+    for frame_len in [16, 256, 4096] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(frame_len),
+            &frame_len,
+            |b, &frame_len| {
+                b.iter_batched(
+                    || port_with_frames(frame_len, 1),
+                    |mut port| while port.read_frame().is_ok() {},
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_read, bench_read_frame);
+criterion_main!(benches);
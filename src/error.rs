@@ -0,0 +1,64 @@
+use alloc::string::String;
+use core::fmt;
+
+/// Stand-in for `std::io::ErrorKind` when building without `std`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    NotConnected,
+    BrokenPipe,
+    ResourceBusy,
+    QuotaExceeded,
+    WouldBlock,
+    InvalidInput,
+    InvalidData,
+    TimedOut,
+    Interrupted,
+    Unsupported,
+    UnexpectedEof,
+    Other,
+}
+
+/// Stand-in for `std::io::Error` when building without `std`.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Error {
+    pub fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn other<M: Into<String>>(message: M) -> Self {
+        Self::new(ErrorKind::Other, message)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind, String::new())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{:?}", self.kind)
+        } else {
+            f.write_str(&self.message)
+        }
+    }
+}
+
+impl core::error::Error for Error {}
@@ -1,9 +1,143 @@
-use std::collections::VecDeque;
-use std::io::{Error, ErrorKind};
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+mod error;
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::ops::Range;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::sync::mpsc::Receiver;
+#[cfg(feature = "std")]
+use std::sync::{Condvar, Mutex};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(not(feature = "std"))]
+pub use error::{Error, ErrorKind};
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind};
+
+/// Current time for the timing features. Under the `tokio` feature this
+/// follows the tokio clock, so `tokio::time::pause()` also holds back
+/// delayed responses.
+#[cfg(feature = "std")]
+fn now() -> Instant {
+    #[cfg(feature = "tokio")]
+    return tokio::time::Instant::now().into_std();
+    #[cfg(not(feature = "tokio"))]
+    Instant::now()
+}
+
+/// Time source for the timing features, see `MockableSerial::set_clock`.
+#[cfg(feature = "std")]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, d: Duration);
+}
+
+/// The real clock. Under the `tokio` feature it follows the tokio clock.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        now()
+    }
+
+    fn sleep(&self, d: Duration) {
+        std::thread::sleep(d);
+    }
+}
+
+/// A clock that only moves when told to. Sleeping on it advances it by
+/// the requested time instead of blocking, so timeouts run instantly.
+/// Clones share the same time.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+#[cfg(feature = "std")]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.now.lock().unwrap() += d;
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, d: Duration) {
+        self.advance(d);
+    }
+}
+
+/// Another clock running `ppm` parts per million fast, or slow when
+/// negative, from `origin` on, see `MockableSerial::set_clock_drift`.
+#[cfg(feature = "std")]
+struct DriftingClock {
+    inner: Arc<dyn Clock>,
+    origin: Instant,
+    ppm: i32,
+}
+
+#[cfg(feature = "std")]
+impl Clock for DriftingClock {
+    fn now(&self) -> Instant {
+        let nominal = self
+            .inner
+            .now()
+            .saturating_duration_since(self.origin)
+            .as_nanos() as i128;
+        let drifted = nominal + nominal * self.ppm as i128 / 1_000_000;
+        self.origin + Duration::from_nanos(drifted.max(0) as u64)
+    }
+
+    fn sleep(&self, d: Duration) {
+        let nanos = d.as_nanos() as i128 * 1_000_000 / (1_000_000 + self.ppm as i128).max(1);
+        self.inner.sleep(Duration::from_nanos(nanos as u64));
+    }
+}
+
+pub const XON: u8 = 0x11;
+pub const XOFF: u8 = 0x13;
+
+pub const DEFAULT_ADDRESS: &str = "/dev/null";
+pub const DEFAULT_BAUD: u32 = 115200;
+pub const DEFAULT_STOP_BYTE: u8 = b'#';
 
 pub struct MockableSerialBuilder {}
 
 impl MockableSerialBuilder {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(
         address: &str,
         baud: u32,
@@ -23,38 +157,931 @@ impl MockableSerialBuilder {
     }
 }
 
+/// One read or write, as captured by the recording mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Interaction {
+    Read(Vec<u8>),
+    Write(Vec<u8>),
+}
+
+/// The direction of an `Interaction`, without its bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InteractionKind {
+    Read,
+    Write,
+}
+
+impl Interaction {
+    pub fn kind(&self) -> InteractionKind {
+        match self {
+            Interaction::Read(_) => InteractionKind::Read,
+            Interaction::Write(_) => InteractionKind::Write,
+        }
+    }
+}
+
+/// USB identifiers of a port, as `serialport` reports them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbPortInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+}
+
+/// Whether dotted version `v` is at least `min`. A leading `v` is
+/// ignored, missing parts count as 0 and a pre-release or build suffix is
+/// not considered.
+fn version_at_least(v: &str, min: &str) -> bool {
+    let parts = |s: &str| -> Vec<u64> {
+        s.trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or("")
+            .split('.')
+            .map(|p| p.parse().unwrap_or(0))
+            .collect()
+    };
+    let (v, min) = (parts(v), parts(min));
+
+    for i in 0..v.len().max(min.len()) {
+        let (a, b) = (v.get(i).unwrap_or(&0), min.get(i).unwrap_or(&0));
+        if a != b {
+            return a > b;
+        }
+    }
+
+    true
+}
+
+/// Compare two recorded transcripts, describing the first difference.
+pub fn recordings_equal(a: &[Interaction], b: &[Interaction]) -> Result<(), String> {
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        let (dir, xs, ys) = match (x, y) {
+            (Interaction::Read(xs), Interaction::Read(ys)) => ("read", xs, ys),
+            (Interaction::Write(xs), Interaction::Write(ys)) => ("write", xs, ys),
+            _ => return Err(format!("interaction {}: {:02x?} vs {:02x?}", i, x, y)),
+        };
+        if xs != ys {
+            let offset = xs
+                .iter()
+                .zip(ys)
+                .position(|(p, q)| p != q)
+                .unwrap_or(xs.len().min(ys.len()));
+            return Err(format!(
+                "interaction {} ({}) differs at byte {}: {:02x?} vs {:02x?}",
+                i, dir, offset, xs, ys
+            ));
+        }
+    }
+
+    if a.len() != b.len() {
+        return Err(format!(
+            "transcripts have {} and {} interactions",
+            a.len(),
+            b.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// A read failure, split into the line conditions a UART reports and
+/// everything else.
+#[derive(Debug)]
+pub enum SerialError {
+    Io(Error),
+    Framing,
+    Parity,
+    Overrun,
+    Break,
+}
+
+impl core::fmt::Display for SerialError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SerialError::Io(e) => e.fmt(f),
+            SerialError::Framing => f.write_str("Framing error"),
+            SerialError::Parity => f.write_str("Parity error"),
+            SerialError::Overrun => f.write_str("Overrun error"),
+            SerialError::Break => f.write_str("Break condition"),
+        }
+    }
+}
+
+impl core::error::Error for SerialError {}
+
+impl From<Error> for SerialError {
+    fn from(e: Error) -> Self {
+        SerialError::Io(e)
+    }
+}
+
+/// Line conditions show up as `InvalidData` to plain `read` callers.
+impl From<SerialError> for Error {
+    fn from(e: SerialError) -> Self {
+        match e {
+            SerialError::Io(e) => e,
+            e => Error::new(ErrorKind::InvalidData, format!("{}", e)),
+        }
+    }
+}
+
+/// Injected line condition waiting for the next read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineFault {
+    Framing,
+    Parity,
+    Overrun,
+    Break,
+}
+
+impl From<LineFault> for SerialError {
+    fn from(f: LineFault) -> Self {
+        match f {
+            LineFault::Framing => SerialError::Framing,
+            LineFault::Parity => SerialError::Parity,
+            LineFault::Overrun => SerialError::Overrun,
+            LineFault::Break => SerialError::Break,
+        }
+    }
+}
+
+/// CRC-16/MODBUS of `data`.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Check a frame whose last two bytes are the little-endian `crc16` of
+/// the rest.
+pub fn verify_crc16(frame: &[u8]) -> bool {
+    match frame.len().checked_sub(2) {
+        Some(n) => crc16(&frame[..n]).to_le_bytes() == frame[n..],
+        None => false,
+    }
+}
+
+/// COBS-encode `data`. The result has no zero bytes; the frame delimiter
+/// is not included.
+pub fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 1);
+    let mut code_at = 0;
+    let mut code = 1_u8;
+    out.push(0);
+
+    for b in data {
+        if *b != 0 {
+            out.push(*b);
+            code += 1;
+        }
+        if *b == 0 || code == 0xff {
+            out[code_at] = code;
+            code_at = out.len();
+            out.push(0);
+            code = 1;
+        }
+    }
+    out[code_at] = code;
+
+    out
+}
+
+/// Undo `cobs_encode`, `None` if `data` is not valid COBS.
+pub fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        let code = data[i] as usize;
+        if code == 0 || i + code > data.len() {
+            return None;
+        }
+        out.extend_from_slice(&data[i + 1..i + code]);
+        i += code;
+        if code < 0xff && i < data.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
+
+pub const SLIP_END: u8 = 0xc0;
+pub const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+/// SLIP-encode `data` (RFC 1055), escaping END and ESC bytes. The
+/// trailing END is not included.
+pub fn slip_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 2);
+
+    for b in data {
+        match *b {
+            SLIP_END => out.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => out.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+            b => out.push(b),
+        }
+    }
+
+    out
+}
+
+/// Undo `slip_encode`, `None` on a bare END or a bad escape.
+pub fn slip_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+
+    while let Some(b) = bytes.next() {
+        match *b {
+            SLIP_END => return None,
+            SLIP_ESC => match bytes.next() {
+                Some(&SLIP_ESC_END) => out.push(SLIP_END),
+                Some(&SLIP_ESC_ESC) => out.push(SLIP_ESC),
+                _ => return None,
+            },
+            b => out.push(b),
+        }
+    }
+
+    Some(out)
+}
+
+/// Text encodings for binary payloads, see `add_encoded_response`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Two lowercase hex digits per byte.
+    Hex,
+    /// Standard base64 with padding.
+    Base64,
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_text(payload: &[u8], encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Hex => payload
+            .iter()
+            .flat_map(|b| [b >> 4, b & 0xf])
+            .map(|d| b"0123456789abcdef"[d as usize])
+            .collect(),
+        Encoding::Base64 => {
+            let mut out = Vec::with_capacity(payload.len().div_ceil(3) * 4);
+            for chunk in payload.chunks(3) {
+                let n = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+                for i in 0..4 {
+                    if i <= chunk.len() {
+                        out.push(BASE64[(n >> (18 - 6 * i)) as usize & 63]);
+                    } else {
+                        out.push(b'=');
+                    }
+                }
+            }
+            out
+        }
+    }
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    BASE64.iter().position(|b| *b == c).map(|v| v as u32)
+}
+
+/// Decode a frame of `encoding` text back to its payload. Trailing bytes
+/// outside the encoding's alphabet, such as the stop byte, are ignored.
+pub fn decode_frame(frame: &[u8], encoding: Encoding) -> Result<Vec<u8>, Error> {
+    let in_alphabet = |c: &u8| match encoding {
+        Encoding::Hex => c.is_ascii_hexdigit(),
+        Encoding::Base64 => *c == b'=' || base64_value(*c).is_some(),
+    };
+    let end = frame.iter().rposition(in_alphabet).map_or(0, |pos| pos + 1);
+    let text = &frame[..end];
+    let bad = || Error::new(ErrorKind::InvalidData, "Invalid encoded frame");
+
+    match encoding {
+        Encoding::Hex => {
+            if text.len() % 2 != 0 {
+                return Err(bad());
+            }
+            text.chunks(2)
+                .map(|pair| {
+                    let hi = char::from(pair[0]).to_digit(16).ok_or_else(bad)?;
+                    let lo = char::from(pair[1]).to_digit(16).ok_or_else(bad)?;
+                    Ok((hi << 4 | lo) as u8)
+                })
+                .collect()
+        }
+        Encoding::Base64 => {
+            if text.len() % 4 != 0 {
+                return Err(bad());
+            }
+            let quads = text.len() / 4;
+            let mut out = Vec::with_capacity(quads * 3);
+            for (i, quad) in text.chunks(4).enumerate() {
+                let pad = quad.iter().rev().take_while(|c| **c == b'=').count();
+                if pad > 2 || (pad > 0 && i + 1 < quads) {
+                    return Err(bad());
+                }
+                let mut n = 0u32;
+                for c in &quad[..4 - pad] {
+                    n = n << 6 | base64_value(*c).ok_or_else(bad)?;
+                }
+                n <<= 6 * pad as u32;
+                out.extend_from_slice(&[(n >> 16) as u8, (n >> 8) as u8, n as u8][..3 - pad]);
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// How `read_frame` delimits and decodes frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// Frames end with the stop byte and are returned as is.
+    #[default]
+    StopByte,
+    /// Frames are COBS-encoded and end with a zero byte; `read_frame`
+    /// returns the decoded payload.
+    Cobs,
+    /// Frames are SLIP-encoded and end with `SLIP_END`; `read_frame`
+    /// returns the decoded payload.
+    Slip,
+    /// No framing: reads run across stop bytes and `read_frame` returns
+    /// everything pending.
+    Raw,
+}
+
+/// One step of splitmix64, the RNG behind the seeded fault options.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Seeded byte doubling, see `set_byte_duplication`.
+#[derive(Debug, Clone, Copy)]
+struct Duplication {
+    probability: f64,
+    state: u64,
+}
+
+impl Duplication {
+    fn hit(&mut self) -> bool {
+        let draw = (splitmix64(&mut self.state) >> 11) as f64 / (1u64 << 53) as f64;
+        draw < self.probability
+    }
+}
+
+/// Seeded per-frame delay, see `set_jitter`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+struct Jitter {
+    base: Duration,
+    spread: Duration,
+    state: u64,
+}
+
+#[cfg(feature = "std")]
+impl Jitter {
+    fn next_delay(&mut self) -> Duration {
+        let z = splitmix64(&mut self.state);
+        let spread = self.spread.as_nanos() as u64;
+        let offset = z % (2 * spread + 1);
+        if offset >= spread {
+            self.base + Duration::from_nanos(offset - spread)
+        } else {
+            self.base
+                .saturating_sub(Duration::from_nanos(spread - offset))
+        }
+    }
+}
+
+/// A check on written bytes, for `expect_write_matching`.
+pub trait WriteMatcher: core::fmt::Debug {
+    fn matches(&self, bytes: &[u8]) -> bool;
+}
+
+/// Matches a write that starts with the given bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartsWith(pub Vec<u8>);
+
+impl WriteMatcher for StartsWith {
+    fn matches(&self, bytes: &[u8]) -> bool {
+        bytes.starts_with(&self.0)
+    }
+}
+
+/// Matches a write containing the given bytes anywhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contains(pub Vec<u8>);
+
+impl WriteMatcher for Contains {
+    fn matches(&self, bytes: &[u8]) -> bool {
+        self.0.is_empty() || bytes.windows(self.0.len()).any(|w| w == &self.0[..])
+    }
+}
+
+/// Matches a write of exactly the given bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exact(pub Vec<u8>);
+
+impl WriteMatcher for Exact {
+    fn matches(&self, bytes: &[u8]) -> bool {
+        bytes == &self.0[..]
+    }
+}
+
+/// Which end of the response queue `read` takes the next frame from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueOrder {
+    #[default]
+    Fifo,
+    Lifo,
+}
+
+/// What reads report once the port is disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectBehavior {
+    /// `read` returns `Ok(0)`; frame reads fail with `UnexpectedEof`.
+    Eof,
+    /// Reads fail with this kind.
+    Error(ErrorKind),
+}
+
+impl Default for DisconnectBehavior {
+    fn default() -> Self {
+        Self::Error(ErrorKind::NotConnected)
+    }
+}
+
+/// How a write matching several rules picks its response. Exact rules
+/// are considered before prefix rules, each in the order they were added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RuleStrategy {
+    /// The first matching rule; the default.
+    #[default]
+    FirstMatch,
+    /// The last matching rule.
+    LastMatch,
+    /// The rule with the longest request, the first one on a tie.
+    MostSpecific,
+}
+
+/// Parity bit setting of the character format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+/// Line settings applied together with `MockableSerial::configure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+}
+
+/// A frame waiting in the response queue.
+#[derive(Clone, Debug, Default)]
+struct Queued {
+    data: Vec<u8>,
+    /// Ends the frame in place of the stop byte.
+    terminator: Option<u8>,
+    /// Drop the connection once the frame has been read out.
+    drop_after: bool,
+    /// Read out whole, stop bytes inside included.
+    unsegmented: bool,
+    /// Only delivered while the device sits at this bus address.
+    target: Option<u8>,
+}
+
+/// An exact request/response rule.
+#[derive(Clone, Debug, Default)]
+struct Rule {
+    request: Vec<u8>,
+    response: Vec<u8>,
+    /// Only fires while the firmware version is at least this.
+    min_version: Option<String>,
+    /// Hold the response back this long after the matching write.
+    delay: Option<Duration>,
+}
+
+impl From<Vec<u8>> for Queued {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            ..Default::default()
+        }
+    }
+}
+
+type Generator = Box<dyn FnMut() -> Option<Vec<u8>> + Send>;
+
+type EmptyHook = Box<dyn FnMut(&mut MockableSerial) + Send>;
+
+type WriteParser = Box<dyn Fn(&[u8]) -> Option<Box<dyn Any + Send>> + Send>;
+
+/// Key/value state a state machine device keeps between writes.
+pub type State = BTreeMap<String, String>;
+
+/// Closure driving a state machine device, see `set_state_machine`.
+pub type StateMachine = Box<dyn FnMut(&[u8], &mut State) -> Option<Vec<u8>> + Send>;
+
 pub struct MockableSerial {
     address: String,
     baud: u32,
-    actual_success: bool,
-    actual_response: Vec<u8>,
-    response_queue: VecDeque<Vec<u8>>,
+    requested_baud: u32,
+    supported_bauds: Vec<u32>,
+    responsive_baud: Option<u32>,
+    bus_address: Option<u8>,
+    reassembly_terminator: Option<u8>,
+    partial_command: Vec<u8>,
+    #[cfg(feature = "tokio")]
+    return_partial: bool,
+    /// Wakes a pending `poll_read` when the next delayed response is due.
+    #[cfg(feature = "tokio")]
+    read_timer: Option<core::pin::Pin<Box<tokio::time::Sleep>>>,
+    usb_info: Option<UsbPortInfo>,
+    data_bits: u8,
+    parity: Parity,
+    stop_bits: u8,
+    factory_config: Option<SerialConfig>,
+    persistent_config: bool,
+    read_failure: Option<(ErrorKind, String)>,
+    actual_response: VecDeque<u8>,
+    response_queue: VecDeque<Queued>,
+    order: QueueOrder,
+    shuffle: Option<u64>,
     success_queue: VecDeque<(bool, ErrorKind)>,
+    line_faults: VecDeque<LineFault>,
+    byte_faults: BTreeMap<usize, LineFault>,
+    delivered: usize,
+    byte_limit: Option<usize>,
+    session_bytes: usize,
+    frames_delivered: usize,
+    after_read: Vec<(usize, Vec<u8>)>,
+    drop_after_read: bool,
+    unsegmented: bool,
     stop_byte: u8,
     read_n_bytes: u32,
     last_read_index: usize,
+    paused: bool,
+    software_flow_control: bool,
+    xoff: bool,
+    hung: bool,
+    busy: bool,
+    write_capacity: Option<usize>,
+    write_remaining: usize,
+    #[cfg(feature = "std")]
+    delayed_queue: VecDeque<(Instant, Queued)>,
+    rules: Vec<Rule>,
+    prefix_rules: Vec<(Vec<u8>, Vec<u8>)>,
+    cycling_rules: Vec<(Vec<u8>, Vec<Vec<u8>>, usize)>,
+    random_rules: Vec<(Vec<u8>, Vec<Vec<u8>>, u64)>,
+    echo_rules: Vec<(Vec<u8>, Vec<u8>)>,
+    firmware_version: Option<String>,
+    rule_strategy: RuleStrategy,
+    rule_case_insensitive: bool,
+    handshake: Option<(Vec<u8>, Vec<u8>)>,
+    read_granularity: Option<usize>,
+    chunk_sizes: Vec<usize>,
+    chunk_index: usize,
+    /// The bytes of the stop sequence before the stop byte.
+    stop_lead: Vec<u8>,
+    /// The last bytes delivered, as long as `stop_lead`.
+    recent: Vec<u8>,
+    escape_byte: Option<u8>,
+    escape_pending: bool,
+    frame_stop: Option<u8>,
+    device_baud: Option<u32>,
+    tx_baud: Option<u32>,
+    rx_baud: Option<u32>,
+    max_queue_frames: Option<usize>,
+    preamble: Option<u8>,
+    /// The preamble and stop byte `set_delimiters` stands in for.
+    delimited: Option<(Option<u8>, u8)>,
+    keep_end_delimiter: bool,
+    discarded: usize,
+    connected: bool,
+    disconnect_behavior: DisconnectBehavior,
+    fail_next_open: bool,
+    rule_trim: Vec<u8>,
+    written: Vec<Vec<u8>>,
+    reads_served: usize,
+    errors_fired: usize,
+    timeouts: usize,
+    internal_error: Option<String>,
+    strict_misuse: bool,
+    forbid_duplicates: bool,
+    poll_only: bool,
+    read_only: bool,
+    fatal_on_error: bool,
+    wedged: Option<(ErrorKind, String)>,
+    misalignment: usize,
+    flaky: Option<(usize, usize)>,
+    auto_ack: Option<u8>,
+    unknown_response: Option<Vec<u8>>,
+    empty_read_kind: ErrorKind,
+    expected_reads: Vec<Vec<u8>>,
+    expected_writes: Vec<Vec<u8>>,
+    expected_matches: Vec<Arc<dyn WriteMatcher + Send + Sync>>,
+    reset_triggers: Vec<Vec<u8>>,
+    boot_banner: Option<Vec<u8>>,
+    generator: Option<Generator>,
+    state_machine: Option<StateMachine>,
+    write_parser: Option<WriteParser>,
+    on_empty: Option<EmptyHook>,
+    parsed_writes: Vec<Box<dyn Any + Send>>,
+    state: State,
+    exhausted: bool,
+    drop_bad_crc: bool,
+    rx_capacity: Option<usize>,
+    framing: Framing,
+    /// The stop byte to put back when leaving COBS or SLIP framing.
+    framing_stop: Option<u8>,
+    byte_reverse: bool,
+    bit_reversal: bool,
+    duplication: Option<Duplication>,
+    duplicate_pending: Option<(u8, bool)>,
+    newline_normalize: Option<Vec<u8>>,
+    recording_enabled: bool,
+    recording: Vec<Interaction>,
+    #[cfg(feature = "std")]
+    recording_started: Option<Instant>,
+    #[cfg(feature = "std")]
+    recording_until: Option<Instant>,
+    #[cfg(feature = "std")]
+    timed_recording: Vec<(Duration, Interaction)>,
+    #[cfg(feature = "std")]
+    timing_simulation: bool,
+    #[cfg(feature = "std")]
+    write_rate: Option<u32>,
+    #[cfg(feature = "std")]
+    tx_pending: usize,
+    #[cfg(feature = "std")]
+    jitter: Option<Jitter>,
+    #[cfg(feature = "std")]
+    per_byte_latency: Option<Duration>,
+    #[cfg(feature = "std")]
+    latency_profile: Vec<Duration>,
+    #[cfg(feature = "std")]
+    bootloop: Option<(Vec<u8>, Duration, Instant)>,
+    #[cfg(feature = "std")]
+    latency_index: usize,
+    #[cfg(feature = "std")]
+    tee: Option<Arc<Mutex<dyn std::io::Write + Send>>>,
+    #[cfg(feature = "std")]
+    clock: Arc<dyn Clock>,
+    /// The frozen clock and the one it stands in for.
+    #[cfg(feature = "std")]
+    frozen: Option<(ManualClock, Arc<dyn Clock>)>,
+    #[cfg(feature = "std")]
+    undrifted: Option<Arc<dyn Clock>>,
+    #[cfg(feature = "std")]
+    opened_at: Instant,
+    #[cfg(feature = "std")]
+    write_times: Vec<Duration>,
+    #[cfg(feature = "std")]
+    keepalive: Option<(Duration, Instant)>,
+    #[cfg(feature = "std")]
+    min_write_spacing: Option<Duration>,
+    #[cfg(feature = "std")]
+    drop_fast_writes: bool,
+    #[cfg(feature = "std")]
+    dropped_writes: usize,
+    require_flush: bool,
+    unflushed: bool,
+    flush_violations: usize,
+    line_ack: Option<Vec<u8>>,
+    strict_line_ack: bool,
+    #[cfg(feature = "std")]
+    startup_delay: Option<Duration>,
+    #[cfg(feature = "notify")]
+    watch: Option<ResponseWatch>,
+    #[cfg(feature = "std")]
+    receiver: Option<Arc<Mutex<Receiver<Vec<u8>>>>>,
+    #[cfg(feature = "std")]
+    shared_writes: Option<Arc<Mutex<Vec<Vec<u8>>>>>,
+    #[cfg(feature = "serialport")]
+    passthrough: Option<Box<dyn serialport::SerialPort>>,
+    #[cfg(feature = "serialport")]
+    port_timeout: Duration,
 }
 
 pub trait SerialMock {
     fn new(address: &str, baud: u32, stop_byte: u8, read_n_bytes: u32) -> Self;
     fn open_native(&self) -> Self;
-    fn write(&self, _b: &Vec<u8>) -> Result<(), std::io::Error>;
-    fn read(&mut self, buff: &mut [u8]) -> Result<(), std::io::Error>;
+    fn write(&mut self, b: &[u8]) -> Result<usize, Error>;
+    fn read(&mut self, buff: &mut [u8]) -> Result<usize, Error>;
+    /// Queue `r` to be read. A stop byte inside it ends a frame and the
+    /// bytes after it are read as the next frame, they are not dropped.
     fn add_response(&mut self, r: &[u8]);
 }
 
+/// Object-safe subset of the port API, for passing ports around as
+/// `Box<dyn DynSerial>`.
+pub trait DynSerial {
+    fn read(&mut self, buff: &mut [u8]) -> Result<usize, Error>;
+    fn write(&mut self, b: &[u8]) -> Result<usize, Error>;
+    fn bytes_to_read(&self) -> Result<u32, Error>;
+}
+
 impl SerialMock for MockableSerial {
     fn new(address: &str, baud: u32, stop_byte: u8, read_n_bytes: u32) -> Self {
         Self {
             address: address.to_string(),
             baud,
+            requested_baud: baud,
+            supported_bauds: Vec::new(),
+            responsive_baud: None,
+            bus_address: None,
+            reassembly_terminator: None,
+            partial_command: Vec::new(),
+            #[cfg(feature = "tokio")]
+            return_partial: false,
+            #[cfg(feature = "tokio")]
+            read_timer: None,
+            usb_info: None,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            factory_config: None,
+            persistent_config: true,
             stop_byte,
             read_n_bytes,
-            actual_success: true,
-            actual_response: Vec::new(),
+            read_failure: None,
+            actual_response: VecDeque::new(),
             response_queue: VecDeque::new(),
+            order: QueueOrder::Fifo,
+            shuffle: None,
             last_read_index: 0,
             success_queue: VecDeque::new(),
+            line_faults: VecDeque::new(),
+            byte_faults: BTreeMap::new(),
+            delivered: 0,
+            byte_limit: None,
+            session_bytes: 0,
+            frames_delivered: 0,
+            after_read: Vec::new(),
+            drop_after_read: false,
+            unsegmented: false,
+            paused: false,
+            software_flow_control: false,
+            xoff: false,
+            hung: false,
+            busy: false,
+            write_capacity: None,
+            write_remaining: 0,
+            #[cfg(feature = "std")]
+            delayed_queue: VecDeque::new(),
+            rules: Vec::new(),
+            prefix_rules: Vec::new(),
+            cycling_rules: Vec::new(),
+            random_rules: Vec::new(),
+            echo_rules: Vec::new(),
+            firmware_version: None,
+            rule_strategy: RuleStrategy::FirstMatch,
+            rule_case_insensitive: false,
+            handshake: None,
+            read_granularity: None,
+            chunk_sizes: Vec::new(),
+            chunk_index: 0,
+            stop_lead: Vec::new(),
+            recent: Vec::new(),
+            escape_byte: None,
+            escape_pending: false,
+            frame_stop: None,
+            device_baud: None,
+            tx_baud: None,
+            rx_baud: None,
+            max_queue_frames: None,
+            preamble: None,
+            delimited: None,
+            keep_end_delimiter: false,
+            discarded: 0,
+            connected: true,
+            disconnect_behavior: DisconnectBehavior::default(),
+            fail_next_open: false,
+            rule_trim: Vec::new(),
+            written: Vec::new(),
+            reads_served: 0,
+            errors_fired: 0,
+            timeouts: 0,
+            internal_error: None,
+            strict_misuse: false,
+            forbid_duplicates: false,
+            poll_only: false,
+            read_only: false,
+            fatal_on_error: false,
+            wedged: None,
+            misalignment: 0,
+            flaky: None,
+            auto_ack: None,
+            unknown_response: None,
+            empty_read_kind: ErrorKind::WouldBlock,
+            expected_reads: Vec::new(),
+            expected_writes: Vec::new(),
+            expected_matches: Vec::new(),
+            reset_triggers: Vec::new(),
+            boot_banner: None,
+            generator: None,
+            state_machine: None,
+            write_parser: None,
+            parsed_writes: Vec::new(),
+            on_empty: None,
+            state: State::new(),
+            exhausted: false,
+            drop_bad_crc: false,
+            rx_capacity: None,
+            framing: Framing::StopByte,
+            framing_stop: None,
+            byte_reverse: false,
+            bit_reversal: false,
+            duplication: None,
+            duplicate_pending: None,
+            newline_normalize: None,
+            recording_enabled: false,
+            recording: Vec::new(),
+            #[cfg(feature = "std")]
+            recording_started: None,
+            #[cfg(feature = "std")]
+            recording_until: None,
+            #[cfg(feature = "std")]
+            timed_recording: Vec::new(),
+            #[cfg(feature = "std")]
+            timing_simulation: false,
+            #[cfg(feature = "std")]
+            write_rate: None,
+            #[cfg(feature = "std")]
+            tx_pending: 0,
+            #[cfg(feature = "std")]
+            jitter: None,
+            #[cfg(feature = "std")]
+            per_byte_latency: None,
+            #[cfg(feature = "std")]
+            latency_profile: Vec::new(),
+            #[cfg(feature = "std")]
+            bootloop: None,
+            #[cfg(feature = "std")]
+            #[cfg(feature = "std")]
+            latency_index: 0,
+            #[cfg(feature = "std")]
+            tee: None,
+            #[cfg(feature = "std")]
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "std")]
+            frozen: None,
+            #[cfg(feature = "std")]
+            undrifted: None,
+            #[cfg(feature = "std")]
+            opened_at: now(),
+            #[cfg(feature = "std")]
+            write_times: Vec::new(),
+            #[cfg(feature = "std")]
+            keepalive: None,
+            #[cfg(feature = "std")]
+            min_write_spacing: None,
+            #[cfg(feature = "std")]
+            drop_fast_writes: false,
+            #[cfg(feature = "std")]
+            dropped_writes: 0,
+            require_flush: false,
+            unflushed: false,
+            flush_violations: 0,
+            line_ack: None,
+            strict_line_ack: false,
+            #[cfg(feature = "std")]
+            startup_delay: None,
+            #[cfg(feature = "notify")]
+            watch: None,
+            #[cfg(feature = "std")]
+            receiver: None,
+            #[cfg(feature = "std")]
+            shared_writes: None,
+            #[cfg(feature = "serialport")]
+            passthrough: None,
+            #[cfg(feature = "serialport")]
+            port_timeout: Duration::ZERO,
         }
     }
 
@@ -62,138 +1089,7219 @@ impl SerialMock for MockableSerial {
         Self {
             address: self.address.clone(),
             baud: self.baud,
+            requested_baud: self.requested_baud,
+            supported_bauds: self.supported_bauds.clone(),
+            responsive_baud: self.responsive_baud,
+            bus_address: self.bus_address,
+            reassembly_terminator: self.reassembly_terminator,
+            partial_command: self.partial_command.clone(),
+            #[cfg(feature = "tokio")]
+            return_partial: self.return_partial,
+            // The timer belongs to the task polling this handle
+            #[cfg(feature = "tokio")]
+            read_timer: None,
+            usb_info: self.usb_info.clone(),
+            data_bits: self.data_bits,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+            factory_config: self.factory_config,
+            persistent_config: self.persistent_config,
             stop_byte: self.stop_byte,
             read_n_bytes: self.read_n_bytes,
-            actual_success: self.actual_success.clone(),
+            read_failure: self.read_failure.clone(),
             actual_response: self.actual_response.clone(),
             response_queue: self.response_queue.clone(),
+            order: self.order,
+            shuffle: self.shuffle,
             last_read_index: self.last_read_index,
             success_queue: self.success_queue.clone(),
+            line_faults: self.line_faults.clone(),
+            byte_faults: self.byte_faults.clone(),
+            delivered: self.delivered,
+            byte_limit: self.byte_limit,
+            session_bytes: self.session_bytes,
+            frames_delivered: self.frames_delivered,
+            after_read: self.after_read.clone(),
+            drop_after_read: self.drop_after_read,
+            unsegmented: self.unsegmented,
+            paused: self.paused,
+            software_flow_control: self.software_flow_control,
+            xoff: self.xoff,
+            hung: self.hung,
+            busy: self.busy,
+            write_capacity: self.write_capacity,
+            write_remaining: self.write_remaining,
+            #[cfg(feature = "std")]
+            delayed_queue: self.delayed_queue.clone(),
+            rules: self.rules.clone(),
+            prefix_rules: self.prefix_rules.clone(),
+            cycling_rules: self.cycling_rules.clone(),
+            random_rules: self.random_rules.clone(),
+            echo_rules: self.echo_rules.clone(),
+            firmware_version: self.firmware_version.clone(),
+            rule_strategy: self.rule_strategy,
+            rule_case_insensitive: self.rule_case_insensitive,
+            handshake: self.handshake.clone(),
+            read_granularity: self.read_granularity,
+            chunk_sizes: self.chunk_sizes.clone(),
+            chunk_index: self.chunk_index,
+            stop_lead: self.stop_lead.clone(),
+            recent: self.recent.clone(),
+            escape_byte: self.escape_byte,
+            escape_pending: self.escape_pending,
+            frame_stop: self.frame_stop,
+            device_baud: self.device_baud,
+            tx_baud: self.tx_baud,
+            rx_baud: self.rx_baud,
+            max_queue_frames: self.max_queue_frames,
+            preamble: self.preamble,
+            delimited: self.delimited,
+            keep_end_delimiter: self.keep_end_delimiter,
+            discarded: self.discarded,
+            connected: self.connected,
+            disconnect_behavior: self.disconnect_behavior,
+            fail_next_open: self.fail_next_open,
+            rule_trim: self.rule_trim.clone(),
+            written: self.written.clone(),
+            reads_served: self.reads_served,
+            errors_fired: self.errors_fired,
+            timeouts: self.timeouts,
+            internal_error: self.internal_error.clone(),
+            strict_misuse: self.strict_misuse,
+            forbid_duplicates: self.forbid_duplicates,
+            poll_only: self.poll_only,
+            read_only: self.read_only,
+            fatal_on_error: self.fatal_on_error,
+            wedged: self.wedged.clone(),
+            misalignment: self.misalignment,
+            flaky: self.flaky,
+            auto_ack: self.auto_ack,
+            unknown_response: self.unknown_response.clone(),
+            empty_read_kind: self.empty_read_kind,
+            expected_reads: self.expected_reads.clone(),
+            expected_writes: self.expected_writes.clone(),
+            expected_matches: self.expected_matches.clone(),
+            reset_triggers: self.reset_triggers.clone(),
+            boot_banner: self.boot_banner.clone(),
+            // A closure can't be cloned, the new handle starts without one
+            generator: None,
+            state_machine: None,
+            write_parser: None,
+            parsed_writes: Vec::new(),
+            on_empty: None,
+            state: self.state.clone(),
+            exhausted: self.exhausted,
+            drop_bad_crc: self.drop_bad_crc,
+            rx_capacity: self.rx_capacity,
+            framing: self.framing,
+            framing_stop: self.framing_stop,
+            byte_reverse: self.byte_reverse,
+            bit_reversal: self.bit_reversal,
+            duplication: self.duplication,
+            duplicate_pending: self.duplicate_pending,
+            newline_normalize: self.newline_normalize.clone(),
+            recording_enabled: self.recording_enabled,
+            recording: self.recording.clone(),
+            #[cfg(feature = "std")]
+            recording_started: self.recording_started,
+            #[cfg(feature = "std")]
+            recording_until: self.recording_until,
+            #[cfg(feature = "std")]
+            timed_recording: self.timed_recording.clone(),
+            #[cfg(feature = "std")]
+            timing_simulation: self.timing_simulation,
+            #[cfg(feature = "std")]
+            write_rate: self.write_rate,
+            #[cfg(feature = "std")]
+            tx_pending: self.tx_pending,
+            #[cfg(feature = "std")]
+            jitter: self.jitter,
+            #[cfg(feature = "std")]
+            per_byte_latency: self.per_byte_latency,
+            #[cfg(feature = "std")]
+            latency_profile: self.latency_profile.clone(),
+            #[cfg(feature = "std")]
+            bootloop: self.bootloop.clone(),
+            #[cfg(feature = "std")]
+            #[cfg(feature = "std")]
+            latency_index: self.latency_index,
+            #[cfg(feature = "std")]
+            tee: self.tee.clone(),
+            #[cfg(feature = "std")]
+            clock: self.clock.clone(),
+            #[cfg(feature = "std")]
+            frozen: self.frozen.clone(),
+            #[cfg(feature = "std")]
+            undrifted: self.undrifted.clone(),
+            #[cfg(feature = "std")]
+            opened_at: self.clock.now(),
+            #[cfg(feature = "std")]
+            write_times: self.write_times.clone(),
+            #[cfg(feature = "std")]
+            keepalive: self.keepalive,
+            #[cfg(feature = "std")]
+            min_write_spacing: self.min_write_spacing,
+            #[cfg(feature = "std")]
+            drop_fast_writes: self.drop_fast_writes,
+            #[cfg(feature = "std")]
+            dropped_writes: self.dropped_writes,
+            require_flush: self.require_flush,
+            unflushed: self.unflushed,
+            flush_violations: self.flush_violations,
+            line_ack: self.line_ack.clone(),
+            strict_line_ack: self.strict_line_ack,
+            #[cfg(feature = "std")]
+            startup_delay: self.startup_delay,
+            // The file watcher belongs to the original handle
+            #[cfg(feature = "notify")]
+            watch: None,
+            #[cfg(feature = "std")]
+            receiver: self.receiver.clone(),
+            #[cfg(feature = "std")]
+            shared_writes: self.shared_writes.clone(),
+            // So does the real port behind a passthrough
+            #[cfg(feature = "serialport")]
+            passthrough: None,
+            #[cfg(feature = "serialport")]
+            port_timeout: self.port_timeout,
         }
     }
 
-    fn write(&self, _b: &Vec<u8>) -> Result<(), std::io::Error> {
-        Ok(())
-    }
+    fn write(&mut self, b: &[u8]) -> Result<usize, Error> {
+        self.check_writable()?;
 
-    fn read(&mut self, buff: &mut [u8]) -> Result<(), std::io::Error> {
-        // Fetch a new item from the queue if there is nothing to read
-        {
-            if self.actual_response.is_empty() && !self.response_queue.is_empty() {
-                self.actual_response
-                    .append(&mut self.response_queue.pop_front().unwrap());
+        #[cfg(feature = "std")]
+        if self.write_too_soon() {
+            self.dropped_writes += 1;
+            if self.drop_fast_writes {
+                return Ok(b.len());
             }
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "Write too soon after the previous one",
+            ));
         }
 
-        let v = *self.actual_response.get(self.last_read_index).unwrap();
-        buff[0] = v;
+        if self.strict_line_ack && self.line_ack.is_some() && self.pending_iter().next().is_some() {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "Previous line not acknowledged",
+            ));
+        }
 
-        if v == self.stop_byte {
-            self.last_read_index = 0;
-            self.actual_response.clear();
-        } else {
-            self.last_read_index += 1;
+        if self.forbid_duplicates && self.written.last().is_some_and(|w| w[..] == *b) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Duplicate write"));
         }
 
-        if self.actual_success {
-            return Ok(());
-        } else {
-            return Err(Error::new(ErrorKind::Other, "An error"));
+        let written = match self.write_capacity {
+            Some(_) => {
+                let n = b.len().min(self.write_remaining);
+                self.write_remaining -= n;
+                n
+            }
+            None => b.len(),
         };
-    }
 
-    fn add_response(&mut self, r: &[u8]) {
-        let mut new_resp = Vec::with_capacity(r.len());
+        if self.poll_only {
+            self.release_due_responses();
+            self.drop_unread();
+        }
+
+        self.written.push(b[..written].to_vec());
+        self.session_bytes += written;
+        self.unflushed = true;
+        if let Some(parse) = &self.write_parser {
+            if let Some(cmd) = parse(&b[..written]) {
+                self.parsed_writes.push(cmd);
+            }
+        }
+        #[cfg(feature = "std")]
+        {
+            self.tx_pending += written;
+            self.write_times.push(self.uptime());
+            if let Some(shared) = &self.shared_writes {
+                shared.lock().unwrap().push(b[..written].to_vec());
+            }
+            if let Some((_, last)) = self.keepalive.as_mut() {
+                *last = self.clock.now();
+            }
+            if let Some(rate) = self.write_rate.filter(|r| *r > 0) {
+                self.clock.sleep(Duration::from_nanos(
+                    written as u64 * 1_000_000_000 / rate as u64,
+                ));
+            }
+        }
+        self.record(Interaction::Write(b[..written].to_vec()));
+        #[cfg(feature = "serialport")]
+        if let Some(real) = self.passthrough.as_mut() {
+            std::io::Write::write_all(real, &b[..written])?;
+        }
 
-        for b in r.iter() {
-            new_resp.push(*b);
+        if self.software_flow_control {
+            let mut command = Vec::with_capacity(written);
+            for v in &b[..written] {
+                match *v {
+                    XOFF => self.xoff = true,
+                    XON => self.xoff = false,
+                    v => command.push(v),
+                }
+            }
+            if !command.is_empty() {
+                self.receive(&command);
+            }
+        } else {
+            self.receive(&b[..written]);
+        }
+        if let Some(ack) = self.line_ack.clone() {
+            self.push_response(ack);
         }
-        self.response_queue.push_back(new_resp);
+
+        Ok(written)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::{MockableSerial, MockableSerialBuilder, SerialMock};
-    use std::collections::VecDeque;
+    fn read(&mut self, buff: &mut [u8]) -> Result<usize, Error> {
+        if !self.connected && self.disconnect_behavior == DisconnectBehavior::Eof {
+            return Ok(0);
+        }
+        self.check_readable()?;
+        if let Some(fault) = self.take_byte_fault() {
+            return Err(SerialError::from(fault).into());
+        }
+        if self.on_empty.is_some() && self.peek_byte().is_none() {
+            self.run_on_empty();
+        }
 
-    fn read_resp(p: &mut MockableSerial) -> Vec<u8> {
-        let mut final_buffer = Vec::new();
+        let max = match (self.chunk_sizes.is_empty(), self.read_granularity) {
+            (false, _) => self.chunk_sizes[self.chunk_index % self.chunk_sizes.len()],
+            (true, Some(n)) => n,
+            (true, None) => self.read_n_bytes as usize,
+        }
+        .min(buff.len());
+        let mut n = 0;
 
-        loop {
-            let mut read_buf = [0; 1];
+        // Without a granularity or chunk sizes reads never cross a frame
+        // boundary
+        let bounded = self.read_granularity.is_none()
+            && self.chunk_sizes.is_empty()
+            && self.framing != Framing::Raw;
 
-            match p.read(read_buf.as_mut_slice()) {
-                Ok(_) => {
-                    let byte = read_buf[0];
+        // A doubled byte that did not fit in the last read goes first
+        let mut done = false;
+        if max > 0 {
+            if let Some((v, ends_frame)) = self.duplicate_pending.take() {
+                buff[0] = v;
+                n = 1;
+                done = bounded && ends_frame;
+            }
+        }
 
-                    final_buffer.push(byte);
+        // Nothing is read past a dropped link
+        while !done && n < max && self.connected && self.peek_byte().is_some() {
+            // Stop short of a byte marked with a line error
+            let room = match self.byte_faults.keys().next() {
+                Some(at) if *at == self.delivered => break,
+                Some(at) => (max - n).min(at - self.delivered),
+                None => max - n,
+            };
 
-                    if byte == 0x23 as u8 {
-                        break;
+            let ends_frame = if self.escape_byte.is_some() || self.duplication.is_some() {
+                let Some((v, ends_frame)) = self.next_byte() else {
+                    self.set_internal_error("peeked byte vanished".to_string());
+                    break;
+                };
+                buff[n] = v;
+                n += 1;
+                if self.duplication.as_mut().is_some_and(|d| d.hit()) {
+                    if n < max {
+                        buff[n] = v;
+                        n += 1;
+                    } else {
+                        self.duplicate_pending = Some((v, ends_frame));
                     }
                 }
-                Err(e) => panic!("Unknown error occurred: {:?}", e),
+                ends_frame
+            } else {
+                let (len, ends_frame) = self.run_len(room, bounded);
+                // Cut the first read of a frame short, then stop there
+                let offset = self.misalignment;
+                if offset > 0 && n == 0 && self.last_read_index == 0 && len > offset {
+                    self.take_run(&mut buff[..len - offset], false);
+                    n = len - offset;
+                    break;
+                }
+                self.take_run(&mut buff[n..n + len], ends_frame);
+                n += len;
+                ends_frame
+            };
+
+            if bounded && ends_frame {
+                break;
             }
         }
 
-        final_buffer
-    }
-
-    #[test]
+        if n == 0 && max > 0 {
+            if self.exhausted {
+                return Ok(0);
+            }
+            return Err(Error::new(self.empty_read_kind, "No data available"));
+        }
+        if !self.chunk_sizes.is_empty() {
+            self.chunk_index += 1;
+        }
+
+        if self.observed() {
+            self.record(Interaction::Read(buff[..n].to_vec()));
+        }
+        self.read_served();
+
+        #[cfg(feature = "std")]
+        if self.timing_simulation {
+            self.clock.sleep(self.transmission_time(self.rx_baud(), n));
+        }
+
+        match &self.read_failure {
+            None => Ok(n),
+            Some((kind, message)) => Err(Error::new(*kind, message.clone())),
+        }
+    }
+
+    fn add_response(&mut self, r: &[u8]) {
+        if r.is_empty() {
+            self.misuse("add_response called with an empty response");
+            return;
+        }
+
+        self.push_response(r.to_vec());
+    }
+}
+
+impl MockableSerial {
+    /// Builder-style: the error `read` returns when there is no data,
+    /// `WouldBlock` by default. The waiting reads such as `read_timeout`
+    /// only wait on `WouldBlock`.
+    pub fn empty_read_kind(mut self, kind: ErrorKind) -> Self {
+        self.empty_read_kind = kind;
+        self
+    }
+
+    /// Change the baud rate, clamped to the nearest supported one if
+    /// `set_supported_bauds` was called.
+    pub fn set_baud_rate(&mut self, baud: u32) {
+        self.requested_baud = baud;
+        self.baud = self
+            .supported_bauds
+            .iter()
+            .copied()
+            .min_by_key(|b| b.abs_diff(baud))
+            .unwrap_or(baud);
+    }
+
+    /// Only allow these rates, like a device that can't hit every baud.
+    pub fn set_supported_bauds(&mut self, bauds: &[u32]) {
+        self.supported_bauds = bauds.to_vec();
+        self.supported_bauds.sort_unstable();
+    }
+
+    /// Put the device on a multidrop bus at `addr`: writes whose first byte
+    /// is another address go unanswered.
+    pub fn set_bus_address(&mut self, addr: u8) {
+        self.bus_address = Some(addr);
+    }
+
+    /// Queue `r` for the device at `target`. When it comes up for reading
+    /// it is dropped unless the bus address is `target` by then.
+    pub fn add_addressed_response(&mut self, target: u8, r: &[u8]) {
+        if r.is_empty() {
+            self.misuse("add_addressed_response called with an empty response");
+            return;
+        }
+
+        self.push_response(Queued {
+            data: r.to_vec(),
+            target: Some(target),
+            ..Default::default()
+        });
+    }
+
+    /// Whether queued frame `r` is meant for this device.
+    fn addressed_here(&self, r: &Queued) -> bool {
+        match (r.target, self.bus_address) {
+            (Some(target), Some(addr)) => target == addr,
+            _ => true,
+        }
+    }
+
+    /// Only answer commands while the port runs at `baud`. At any other
+    /// rate the device can't make sense of a write, so reads time out.
+    pub fn set_responsive_baud(&mut self, baud: u32) {
+        self.responsive_baud = Some(baud);
+    }
+
+    /// The rate last asked for with `set_baud_rate`.
+    pub fn requested_baud(&self) -> u32 {
+        self.requested_baud
+    }
+
+    /// The rate the port really runs at.
+    pub fn effective_baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Send at `baud` instead of the port's baud, for split rate links.
+    pub fn set_tx_baud(&mut self, baud: u32) {
+        self.tx_baud = Some(baud);
+    }
+
+    /// Receive at `baud` instead of the port's baud, for split rate links.
+    pub fn set_rx_baud(&mut self, baud: u32) {
+        self.rx_baud = Some(baud);
+    }
+
+    pub fn tx_baud(&self) -> u32 {
+        self.tx_baud.unwrap_or(self.baud)
+    }
+
+    pub fn rx_baud(&self) -> u32 {
+        self.rx_baud.unwrap_or(self.baud)
+    }
+
+    /// Data bits per character, 8 by default.
+    pub fn set_data_bits(&mut self, bits: u8) {
+        self.data_bits = bits;
+    }
+
+    /// Parity bit per character, none by default.
+    pub fn set_parity(&mut self, parity: Parity) {
+        self.parity = parity;
+    }
+
+    /// Stop bits per character, 1 by default.
+    pub fn set_stop_bits(&mut self, bits: u8) {
+        self.stop_bits = bits;
+    }
+
+    pub fn data_bits(&self) -> u8 {
+        self.data_bits
+    }
+
+    pub fn parity(&self) -> Parity {
+        self.parity
+    }
+
+    pub fn stop_bits(&self) -> u8 {
+        self.stop_bits
+    }
+
+    /// Apply all line settings at once, as a driver configuring the device.
+    pub fn configure(&mut self, config: SerialConfig) {
+        if self.factory_config.is_none() {
+            self.factory_config = Some(self.config());
+        }
+        self.set_baud_rate(config.baud);
+        self.data_bits = config.data_bits;
+        self.parity = config.parity;
+        self.stop_bits = config.stop_bits;
+    }
+
+    /// The line settings in effect.
+    pub fn config(&self) -> SerialConfig {
+        SerialConfig {
+            baud: self.baud,
+            data_bits: self.data_bits,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+        }
+    }
+
+    /// Whether settings from `configure` survive a device reset, as with
+    /// NVRAM, the default. Without, a reset restores the settings from
+    /// before the first `configure`.
+    pub fn set_persistent_config(&mut self, persistent: bool) {
+        self.persistent_config = persistent;
+    }
+
+    /// Hold back all data: reads return `WouldBlock` until `resume` is called.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Model a wedged device: reads find nothing, even with data queued, so
+    /// `read_timeout` always runs out its full timeout.
+    pub fn set_hang(&mut self, enabled: bool) {
+        self.hung = enabled;
+    }
+
+    /// Bound the response queue used by `try_add_response`.
+    pub fn set_max_queue_frames(&mut self, n: usize) {
+        self.max_queue_frames = Some(n);
+    }
+
+    /// Like `add_response`, but fail with `WouldBlock` when the queue is
+    /// full, or `InvalidInput` on an empty response.
+    pub fn try_add_response(&mut self, r: &[u8]) -> Result<(), Error> {
+        if r.is_empty() {
+            return Err(self.misuse("add_response called with an empty response"));
+        }
+        if let Some(max) = self.max_queue_frames {
+            if self.response_queue.len() >= max {
+                return Err(Error::new(ErrorKind::WouldBlock, "Response queue is full"));
+            }
+        }
+
+        self.add_response(r);
+        Ok(())
+    }
+
+    /// Queue `partial` as an unterminated frame and drop the connection once
+    /// it has been read. `read_frame` returns the partial frame, then
+    /// `NotConnected`.
+    pub fn add_truncated_response(&mut self, partial: &[u8]) {
+        self.response_queue.push_back(Queued {
+            data: partial.to_vec(),
+            drop_after: true,
+            ..Default::default()
+        });
+    }
+
+    /// Push `data` ahead of every queued response, as if the device sent it
+    /// on its own. A frame already being read is finished first.
+    pub fn inject_unsolicited(&mut self, data: &[u8]) {
+        if self.passes_crc(data) {
+            self.push_next(data.to_vec());
+        }
+    }
+
+    /// Silently drop every frame whose trailing CRC (before the stop byte,
+    /// see `verify_crc16`) is wrong, as if the device never sent it.
+    pub fn set_drop_bad_crc(&mut self) {
+        self.drop_bad_crc = true;
+
+        let mut queue = core::mem::take(&mut self.response_queue);
+        queue.retain(|r| self.passes_crc(&r.data));
+        self.response_queue = queue;
+    }
+
+    /// Hold at most `n` unread bytes. Bytes arriving beyond that are lost
+    /// and the next read reports an overrun.
+    pub fn set_rx_capacity(&mut self, n: usize) {
+        self.rx_capacity = Some(n);
+    }
+
+    /// Queue a response from `add_response`, after its jitter and
+    /// length-proportional delays if set.
+    fn push_response(&mut self, r: impl Into<Queued>) {
+        let r = r.into();
+        #[cfg(feature = "std")]
+        let delay = match (self.jitter.as_mut(), self.per_byte_latency) {
+            (None, None) => None,
+            (jitter, per_byte) => Some(
+                jitter.map_or(Duration::ZERO, |j| j.next_delay())
+                    + per_byte.unwrap_or_default() * r.data.len() as u32,
+            ),
+        };
+        #[cfg(feature = "std")]
+        if let Some(delay) = delay {
+            // Frames still arrive in order, a later one never overtakes
+            let due = self.clock.now() + delay;
+            let due = match self.delayed_queue.back() {
+                Some((last, _)) => due.max(*last),
+                None => due,
+            };
+            self.delayed_queue.push_back((due, r));
+            return;
+        }
+        self.enqueue(r);
+    }
+
+    /// Queue a frame arriving from the device, applying the CRC filter and
+    /// the receive buffer limit.
+    fn enqueue(&mut self, r: impl Into<Queued>) {
+        let mut r = r.into();
+        if !self.passes_crc(&r.data) {
+            return;
+        }
+
+        if let Some(cap) = self.rx_capacity {
+            let free = cap.saturating_sub(self.pending_iter().count());
+            if r.data.len() > free {
+                r.data.truncate(free);
+                if !self.line_faults.contains(&LineFault::Overrun) {
+                    self.line_faults.push_back(LineFault::Overrun);
+                }
+            }
+            if r.data.is_empty() {
+                return;
+            }
+        }
+
+        self.response_queue.push_back(r);
+    }
+
+    fn passes_crc(&self, frame: &[u8]) -> bool {
+        if !self.drop_bad_crc {
+            return true;
+        }
+
+        match frame.split_last() {
+            Some((last, rest)) if *last == self.stop_byte => verify_crc16(rest),
+            _ => verify_crc16(frame),
+        }
+    }
+
+    /// Queue `response` every time exactly `request` is written.
+    pub fn add_rule(&mut self, request: &[u8], response: &[u8]) {
+        self.rules.push(Rule {
+            request: request.to_vec(),
+            response: response.to_vec(),
+            ..Default::default()
+        });
+    }
+
+    /// The device's firmware version, checked by `add_rule_for_version`.
+    pub fn set_firmware_version(&mut self, v: &str) {
+        self.firmware_version = Some(v.to_string());
+    }
+
+    /// Like `add_rule`, but the rule only fires while the firmware version
+    /// is at least `min_version`. Versions compare numerically part by
+    /// part, so `"1.10"` is newer than `"1.9"`.
+    pub fn add_rule_for_version(&mut self, min_version: &str, request: &[u8], response: &[u8]) {
+        self.rules.push(Rule {
+            request: request.to_vec(),
+            response: response.to_vec(),
+            min_version: Some(min_version.to_string()),
+            ..Default::default()
+        });
+    }
+
+    /// Whether the firmware version allows a rule needing `min` to fire.
+    fn rule_enabled(&self, min: Option<&str>) -> bool {
+        match (min, &self.firmware_version) {
+            (None, _) => true,
+            (Some(min), Some(v)) => version_at_least(v, min),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Queue `response` for any write starting with `prefix` that no exact
+    /// rule matches.
+    pub fn add_prefix_rule(&mut self, prefix: &[u8], response: &[u8]) {
+        self.prefix_rules.push((prefix.to_vec(), response.to_vec()));
+    }
+
+    /// Ignore every write and hold back all responses until `expected` is
+    /// written, then answer it with `reply` and behave normally.
+    pub fn require_handshake(&mut self, expected: &[u8], reply: &[u8]) {
+        self.handshake = Some((expected.to_vec(), reply.to_vec()));
+    }
+
+    /// Choose how overlapping rules are resolved, `FirstMatch` by default.
+    pub fn set_rule_strategy(&mut self, strategy: RuleStrategy) {
+        self.rule_strategy = strategy;
+    }
+
+    /// Fold ASCII case when matching writes against exact and prefix rules.
+    pub fn set_rule_case_insensitive(&mut self, enabled: bool) {
+        self.rule_case_insensitive = enabled;
+    }
+
+    /// Answer a write of exactly `request` with an echo of it, read back as
+    /// a frame of its own, followed by `response`.
+    pub fn add_echo_rule(&mut self, request: &[u8], response: &[u8]) {
+        self.echo_rules.push((request.to_vec(), response.to_vec()));
+    }
+
+    /// Answer each write of exactly `request` with the next of `responses`,
+    /// starting over after the last. Checked before the other rules.
+    pub fn add_cycling_rule(&mut self, request: &[u8], responses: Vec<Vec<u8>>) {
+        self.cycling_rules.push((request.to_vec(), responses, 0));
+    }
+
+    /// Answer each write of exactly `request` with one of `choices`, picked
+    /// by a generator seeded with `seed`.
+    pub fn add_random_choice_rule(&mut self, request: &[u8], choices: Vec<Vec<u8>>, seed: u64) {
+        self.random_rules.push((request.to_vec(), choices, seed));
+    }
+
+    /// The exact rules, in the order they were added.
+    pub fn rules(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.rules
+            .iter()
+            .map(|r| (r.request.clone(), r.response.clone()))
+            .collect()
+    }
+
+    /// The prefix rules, in the order they were added.
+    pub fn prefix_rules(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.prefix_rules.clone()
+    }
+
+    /// Every write so far, in order.
+    pub fn written_messages(&self) -> &[Vec<u8>] {
+        &self.written
+    }
+
+    /// Decode every write with `f` and keep the commands it returns, see
+    /// `parsed_writes`.
+    pub fn set_write_parser<C, F>(&mut self, f: F)
+    where
+        C: Any + Send,
+        F: Fn(&[u8]) -> Option<C> + Send + 'static,
+    {
+        self.write_parser = Some(Box::new(move |b: &[u8]| {
+            f(b).map(|c| Box::new(c) as Box<dyn Any + Send>)
+        }));
+    }
+
+    /// The commands the write parser decoded so far that are of type `C`.
+    pub fn parsed_writes<C: Any>(&self) -> Vec<&C> {
+        self.parsed_writes
+            .iter()
+            .filter_map(|c| c.downcast_ref::<C>())
+            .collect()
+    }
+
+    /// Every written byte in order, write boundaries dropped.
+    pub fn written_stream(&self) -> Vec<u8> {
+        self.written.concat()
+    }
+
+    /// `written_stream`, clearing the captured writes.
+    pub fn take_written_stream(&mut self) -> Vec<u8> {
+        #[cfg(feature = "std")]
+        self.write_times.clear();
+        core::mem::take(&mut self.written).concat()
+    }
+
+    /// Whether `needle` shows up anywhere in the written bytes, even split
+    /// across writes.
+    pub fn was_written(&self, needle: &[u8]) -> bool {
+        let all = self.written.concat();
+        needle.is_empty() || all.windows(needle.len()).any(|w| w == needle)
+    }
+
+    /// Panic unless `bytes` was written at some point.
+    pub fn assert_wrote(&mut self, bytes: &[u8]) -> &mut Self {
+        assert!(
+            self.written.iter().any(|w| w[..] == *bytes),
+            "expected a write of {:02x?}, got {:02x?}",
+            bytes,
+            self.written
+        );
+        self
+    }
+
+    /// A few lines summing up the session so far, for debugging a test.
+    pub fn session_report(&self) -> String {
+        let written: usize = self.written.iter().map(Vec::len).sum();
+        format!(
+            "reads: {} ({} bytes)\nwrites: {} ({} bytes)\ninjected errors: {}\ntimeouts: {}\nqueued frames: {}\n",
+            self.reads_served,
+            self.delivered,
+            self.written.len(),
+            written,
+            self.errors_fired,
+            self.timeouts,
+            self.queued_order().len()
+        )
+    }
+
+    /// Panic if any read returned data or any write was made.
+    pub fn assert_idle(&self) {
+        assert!(
+            self.reads_served == 0 && self.written.is_empty(),
+            "expected no I/O, got {} reads and {} writes",
+            self.reads_served,
+            self.written.len()
+        );
+    }
+
+    /// Panic unless exactly `n` reads (`read` or `read_frame` calls that
+    /// returned data) have happened.
+    pub fn assert_read_count(&mut self, n: usize) -> &mut Self {
+        assert_eq!(
+            self.reads_served, n,
+            "expected {} reads, got {}",
+            n, self.reads_served
+        );
+        self
+    }
+
+    /// Panic unless `remaining_bytes` is exactly `expected`, naming the
+    /// first differing offset.
+    pub fn assert_remaining_eq(&self, expected: &[u8]) {
+        let remaining = self.remaining_bytes();
+        if remaining[..] != *expected {
+            let at = remaining
+                .iter()
+                .zip(expected)
+                .position(|(a, b)| a != b)
+                .unwrap_or(remaining.len().min(expected.len()));
+            panic!(
+                "remaining bytes differ at offset {}: expected {:02x?}, got {:02x?}",
+                at, expected, remaining
+            );
+        }
+    }
+
+    /// How many writes repeated the write just before them.
+    pub fn duplicate_writes(&self) -> usize {
+        self.written.windows(2).filter(|w| w[0] == w[1]).count()
+    }
+
+    /// Reject a write identical to the previous one with `InvalidInput`.
+    pub fn forbid_duplicate_writes(&mut self) {
+        self.forbid_duplicates = true;
+    }
+
+    /// Queue `r` and require it to be read completely before `verify`.
+    pub fn expect_read(&mut self, r: &[u8]) {
+        self.add_response(r);
+        self.expected_reads.push(r.to_vec());
+    }
+
+    /// Require `b` to be written before `verify`.
+    pub fn expect_write(&mut self, b: &[u8]) {
+        self.expected_writes.push(b.to_vec());
+    }
+
+    /// Require some write accepted by `m` before `verify`.
+    pub fn expect_write_matching<M: WriteMatcher + Send + Sync + 'static>(&mut self, m: M) {
+        self.expected_matches.push(Arc::new(m));
+    }
+
+    /// Check that every `expect_read` frame was consumed and every
+    /// `expect_write` happened, describing what is left over otherwise.
+    pub fn verify(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        let mut pending: Vec<Vec<u8>> =
+            self.response_queue.iter().map(|r| r.data.clone()).collect();
+
+        if !self.actual_response.is_empty() {
+            pending.push(self.actual_response.iter().copied().collect());
+        }
+
+        for r in self.expected_reads.iter() {
+            if let Some(pos) = pending.iter().position(|p| p == r) {
+                pending.remove(pos);
+                problems.push(format!("expected read {:02x?} was not consumed", r));
+            }
+        }
+
+        for b in self.expected_writes.iter() {
+            if !self.written.contains(b) {
+                problems.push(format!("expected write {:02x?} never happened", b));
+            }
+        }
+
+        for m in self.expected_matches.iter() {
+            if !self.written.iter().any(|w| m.matches(w)) {
+                problems.push(format!("no write matched {:02x?}", m));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+
+    /// Check that no queued response has an unescaped stop byte before its
+    /// last byte, which would split it into several frames.
+    pub fn validate_frames(&self) -> Result<(), String> {
+        let queued = self.response_queue.iter();
+        #[cfg(feature = "std")]
+        let queued = queued.chain(self.delayed_queue.iter().map(|(_, r)| r));
+        let mut problems = Vec::new();
+
+        for r in queued.filter(|r| !r.unsegmented) {
+            let stop = self.stop_for(r);
+            let r = &r.data;
+            let mut escaped = false;
+            for (i, b) in r.iter().enumerate() {
+                if !escaped && *b == stop && i + 1 < r.len() {
+                    problems.push(format!("response {:02x?} has a stop byte at {}", r, i));
+                    break;
+                }
+                escaped = !escaped && Some(*b) == self.escape_byte;
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+
+    /// Lint the mock's own setup: queued responses without a terminator,
+    /// rule responses with a stop byte inside, duplicate exact rules and
+    /// rules no write can reach under the current `RuleStrategy`.
+    pub fn validate_scenario(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        for r in self.response_queue.iter().filter(|r| !r.unsegmented) {
+            if r.data.last() != Some(&self.stop_for(r)) {
+                warnings.push(format!(
+                    "response {:02x?} does not end with the stop byte",
+                    r.data
+                ));
+            }
+        }
+
+        let exact = self.rules.iter().map(|r| (&r.request, &r.response));
+        for (req, resp) in exact.chain(self.prefix_rules.iter().map(|(req, resp)| (req, resp))) {
+            let end = resp.len().saturating_sub(1);
+            if let Some(pos) = resp[..end].iter().position(|b| *b == self.stop_byte) {
+                warnings.push(format!(
+                    "rule {:02x?} response has a stop byte at {}",
+                    req, pos
+                ));
+            }
+        }
+
+        for (i, req) in self.rules.iter().map(|r| &r.request).enumerate() {
+            if self.rules[..i].iter().any(|other| other.request == *req) {
+                warnings.push(format!("duplicate rule {:02x?}", req));
+            } else if let Some(winner) = self.select_rule(req) {
+                if !core::ptr::eq(winner.0, &req[..]) {
+                    warnings.push(format!(
+                        "rule {:02x?} is shadowed by prefix rule {:02x?}",
+                        req, winner.0
+                    ));
+                }
+            }
+        }
+
+        // A prefix rule is dead if a rule preferred over it matches
+        // everything it does
+        for (i, (prefix, _)) in self.prefix_rules.iter().enumerate() {
+            let preferred = match self.rule_strategy {
+                RuleStrategy::FirstMatch => &self.prefix_rules[..i],
+                RuleStrategy::LastMatch => &self.prefix_rules[i + 1..],
+                RuleStrategy::MostSpecific => &[],
+            };
+            if let Some((other, _)) = preferred.iter().find(|(p, _)| prefix.starts_with(p)) {
+                warnings.push(format!(
+                    "prefix rule {:02x?} is shadowed by prefix rule {:02x?}",
+                    prefix, other
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    /// Make writing `command` reboot the device, dropping all queued data.
+    pub fn add_reset_trigger(&mut self, command: &[u8]) {
+        self.reset_triggers.push(command.to_vec());
+    }
+
+    /// Data the device sends right after a reset.
+    pub fn set_boot_banner(&mut self, b: &[u8]) {
+        self.boot_banner = Some(b.to_vec());
+    }
+
+    fn device_reset(&mut self) {
+        self.drop_unread();
+        #[cfg(feature = "std")]
+        self.delayed_queue.clear();
+
+        if let Some(banner) = &self.boot_banner {
+            self.response_queue.push_back(banner.clone().into());
+        }
+
+        if !self.persistent_config {
+            if let Some(factory) = self.factory_config.take() {
+                self.configure(factory);
+                // Back on factory settings, nothing left to restore
+                self.factory_config = None;
+            }
+        }
+    }
+
+    /// Throw away everything already received but not yet read.
+    fn drop_unread(&mut self) {
+        self.response_queue.clear();
+        self.actual_response.clear();
+        self.last_read_index = 0;
+        self.frame_stop = None;
+        self.drop_after_read = false;
+        self.unsegmented = false;
+        self.duplicate_pending = None;
+        self.recent.clear();
+    }
+
+    /// Answer every write no rule or state machine answers with the single
+    /// byte `ack_byte`.
+    pub fn set_auto_ack(&mut self, ack_byte: u8) {
+        self.auto_ack = Some(ack_byte);
+    }
+
+    /// Answer every write no rule or state machine answers with `r`, the
+    /// way a device replies to a command it doesn't know. `set_auto_ack`
+    /// takes precedence.
+    pub fn set_unknown_command_response(&mut self, r: &[u8]) {
+        self.unknown_response = Some(r.to_vec());
+    }
+
+    /// Refuse every write with `PermissionDenied`, like a sensor that
+    /// never takes input.
+    pub fn set_read_only(&mut self, enabled: bool) {
+        self.read_only = enabled;
+    }
+
+    /// Model a device that only answers when polled: a response still
+    /// unread when the next write arrives is discarded.
+    pub fn set_poll_only(&mut self, enabled: bool) {
+        self.poll_only = enabled;
+    }
+
+    /// Start capturing every read and write as an `Interaction`.
+    pub fn enable_recording(&mut self) {
+        self.recording_enabled = true;
+        #[cfg(feature = "std")]
+        {
+            self.recording_until = None;
+        }
+    }
+
+    pub fn recording(&self) -> &[Interaction] {
+        &self.recording
+    }
+
+    /// Panic unless the recording holds reads and writes in exactly the
+    /// order of `expected`, whatever their bytes.
+    pub fn assert_interaction_sequence(&self, expected: &[InteractionKind]) {
+        let kinds: Vec<InteractionKind> = self.recording.iter().map(Interaction::kind).collect();
+        if kinds[..] != *expected {
+            panic!(
+                "interaction sequence differs: expected {:?}, got {:?}",
+                expected, kinds
+            );
+        }
+    }
+
+    /// Whether anything wants to see interactions, so they are worth copying.
+    fn observed(&self) -> bool {
+        #[cfg(feature = "std")]
+        if self.tee.is_some() {
+            return true;
+        }
+
+        self.recording_enabled
+    }
+
+    fn record(&mut self, i: Interaction) {
+        #[cfg(feature = "std")]
+        if let Some(tee) = &self.tee {
+            let (dir, bytes) = match &i {
+                Interaction::Read(b) => ("RX", b),
+                Interaction::Write(b) => ("TX", b),
+            };
+            let mut line = String::from(dir);
+            for b in bytes {
+                line.push_str(&format!(" {:02x}", b));
+            }
+            line.push('\n');
+            // A broken sink must not break the port
+            let _ = tee.lock().unwrap().write_all(line.as_bytes());
+        }
+
+        if !self.recording_enabled {
+            return;
+        }
+
+        #[cfg(feature = "std")]
+        if self
+            .recording_until
+            .is_some_and(|until| self.clock.now() >= until)
+        {
+            self.recording_enabled = false;
+            self.recording_until = None;
+            return;
+        }
+
+        #[cfg(feature = "std")]
+        if let Some(started) = self.recording_started {
+            self.timed_recording
+                .push((self.clock.now() - started, i.clone()));
+        }
+        self.recording.push(i);
+    }
+
+    /// Strip any of `trim_bytes` from the end of writes before matching rules.
+    pub fn set_rule_trim(&mut self, trim_bytes: &[u8]) {
+        self.rule_trim = trim_bytes.to_vec();
+    }
+
+    /// Collect writes until `terminator` is written and match the bytes
+    /// up to and including it as one command.
+    pub fn set_write_reassembly(&mut self, terminator: u8) {
+        self.reassembly_terminator = Some(terminator);
+    }
+
+    /// Pass written data on as commands, reassembling them if asked to.
+    fn receive(&mut self, data: &[u8]) {
+        let Some(terminator) = self.reassembly_terminator else {
+            self.handle_command(data);
+            return;
+        };
+
+        for &v in data {
+            self.partial_command.push(v);
+            if v == terminator {
+                let command = core::mem::take(&mut self.partial_command);
+                self.handle_command(&command);
+            }
+        }
+    }
+
+    /// React to a write the device accepted.
+    fn handle_command(&mut self, command: &[u8]) {
+        if self.responsive_baud.is_some_and(|b| b != self.baud) {
+            return;
+        }
+        if let Some(addr) = self.bus_address {
+            if command.first() != Some(&addr) {
+                return;
+            }
+        }
+
+        if let Some((expected, reply)) = &self.handshake {
+            if self.trim_command(command) == &expected[..] {
+                let reply = reply.clone();
+                self.handshake = None;
+                self.push_next(reply);
+            }
+            return;
+        }
+
+        if self.reset_triggers.iter().any(|t| t[..] == *command) {
+            self.device_reset();
+            return;
+        }
+
+        if let Some(sm) = self.state_machine.as_mut() {
+            if let Some(resp) = sm(command, &mut self.state) {
+                self.enqueue(resp);
+                return;
+            }
+        }
+
+        let key = self.trim_command(command);
+        if let Some((_, resp)) = self.echo_rules.iter().find(|(req, _)| req[..] == *key) {
+            let resp = resp.clone();
+            // The echo is a frame of its own, whatever it ends with
+            self.enqueue(Queued {
+                data: command.to_vec(),
+                terminator: command.last().copied(),
+                ..Default::default()
+            });
+            self.enqueue(resp);
+            return;
+        }
+
+        if let Some((_, responses, next)) = self
+            .cycling_rules
+            .iter_mut()
+            .find(|(req, responses, _)| req[..] == *key && !responses.is_empty())
+        {
+            let resp = responses[*next % responses.len()].clone();
+            *next += 1;
+            self.enqueue(resp);
+            return;
+        }
+
+        if let Some((_, choices, state)) = self
+            .random_rules
+            .iter_mut()
+            .find(|(req, choices, _)| req[..] == *key && !choices.is_empty())
+        {
+            let at = splitmix64(state) % choices.len() as u64;
+            let resp = choices[at as usize].clone();
+            self.enqueue(resp);
+            return;
+        }
+
+        if let Some((_, resp, _rule_delay)) = self.select_rule(key) {
+            let resp = resp.to_vec();
+            #[cfg(feature = "std")]
+            if let Some(delay) = _rule_delay {
+                self.add_delayed_response(&resp, delay);
+                return;
+            }
+            #[cfg(feature = "std")]
+            if !self.latency_profile.is_empty() {
+                let delay = self.latency_profile[self.latency_index % self.latency_profile.len()];
+                self.latency_index += 1;
+                self.add_delayed_response(&resp, delay);
+                return;
+            }
+            self.enqueue(resp);
+            return;
+        }
+
+        if let Some(ack) = self.auto_ack {
+            self.enqueue(Vec::from([ack]));
+        } else if let Some(r) = self.unknown_response.clone() {
+            self.enqueue(r);
+        }
+    }
+
+    /// The rule answering `key` under the configured `RuleStrategy`.
+    fn select_rule(&self, key: &[u8]) -> Option<(&[u8], &[u8], Option<Duration>)> {
+        let eq = |a: &[u8], b: &[u8]| {
+            if self.rule_case_insensitive {
+                a.eq_ignore_ascii_case(b)
+            } else {
+                a == b
+            }
+        };
+        let mut candidates = self
+            .rules
+            .iter()
+            .filter(|r| eq(&r.request, key) && self.rule_enabled(r.min_version.as_deref()))
+            .map(|r| (&r.request[..], &r.response[..], r.delay))
+            .chain(
+                self.prefix_rules
+                    .iter()
+                    .filter(|(prefix, _)| {
+                        key.len() >= prefix.len() && eq(&key[..prefix.len()], prefix)
+                    })
+                    .map(|(prefix, resp)| (&prefix[..], &resp[..], None)),
+            );
+
+        match self.rule_strategy {
+            RuleStrategy::FirstMatch => candidates.next(),
+            RuleStrategy::LastMatch => candidates.last(),
+            RuleStrategy::MostSpecific => {
+                candidates.fold(None, |best: Option<(&[u8], &[u8], _)>, c| match best {
+                    Some(b) if b.0.len() >= c.0.len() => Some(b),
+                    _ => Some(c),
+                })
+            }
+        }
+    }
+
+    fn trim_command<'a>(&self, command: &'a [u8]) -> &'a [u8] {
+        let mut end = command.len();
+
+        while end > 0 && self.rule_trim.contains(&command[end - 1]) {
+            end -= 1;
+        }
+
+        &command[..end]
+    }
+
+    /// The frames still to be delivered, in the order reads will see them:
+    /// the rest of the frame being read, the queue, then delayed responses.
+    pub fn queued_order(&self) -> Vec<Vec<u8>> {
+        let mut order = Vec::new();
+
+        let current: Vec<u8> = self
+            .actual_response
+            .range(self.last_read_index..)
+            .copied()
+            .collect();
+        if !current.is_empty() {
+            order.push(current);
+        }
+        order.extend(self.queue_iter().map(|r| r.data.clone()));
+        #[cfg(feature = "std")]
+        order.extend(self.delayed_queue.iter().map(|(_, r)| r.data.clone()));
+
+        order
+    }
+
+    /// Read byte by byte, ending once no more data is available.
+    pub fn bytes(&mut self) -> ByteIter<'_> {
+        ByteIter { port: self }
+    }
+
+    /// Everything still to be delivered from the queue, as one flat byte
+    /// string: the rest of the current frame followed by the queued frames.
+    pub fn remaining_bytes(&self) -> Vec<u8> {
+        self.pending_iter().collect()
+    }
+
+    /// Deliver queued responses oldest first (`Fifo`, the default) or
+    /// newest first (`Lifo`).
+    pub fn set_order(&mut self, order: QueueOrder) {
+        self.order = order;
+    }
+
+    /// Queue `r` where the next read takes it from, ahead of everything
+    /// already queued.
+    fn push_next(&mut self, r: impl Into<Queued>) {
+        match self.order {
+            QueueOrder::Fifo => self.response_queue.push_front(r.into()),
+            QueueOrder::Lifo => self.response_queue.push_back(r.into()),
+        }
+    }
+
+    /// Deliver queued responses in an order shuffled by a generator seeded
+    /// with `seed`, instead of the one `set_order` picks.
+    pub fn set_shuffle(&mut self, seed: u64) {
+        self.shuffle = Some(seed);
+    }
+
+    /// Make the first `read` of each frame return `offset` bytes fewer than
+    /// it otherwise would, so later reads straddle the frame boundary.
+    pub fn set_read_misalignment(&mut self, offset: usize) {
+        self.misalignment = offset;
+    }
+
+    /// Let each `read` return up to `n` bytes, even across frame boundaries.
+    pub fn set_read_granularity(&mut self, n: usize) {
+        self.read_granularity = Some(n);
+    }
+
+    /// Let each `read` return up to the next of `sizes` bytes, starting over
+    /// after the last. Like `set_read_granularity`, reads cross frame
+    /// boundaries. Takes precedence over the granularity.
+    pub fn set_chunk_sizes(&mut self, sizes: Vec<usize>) {
+        self.chunk_sizes = sizes;
+        self.chunk_index = 0;
+    }
+
+    /// The original single-byte read: one byte into `buff[0]`, nothing
+    /// reported back but success or failure.
+    pub fn read_byte(&mut self, buff: &mut [u8]) -> Result<(), Error> {
+        let Some(out) = buff.get_mut(..1) else {
+            return Err(self.misuse("read_byte called with an empty buffer"));
+        };
+        SerialMock::read(self, out).map(|_| ())
+    }
+
+    /// Panic on misuse of the mock, such as an empty `add_response` or a
+    /// `read_byte` into an empty buffer, instead of the default: the call
+    /// fails with `InvalidInput` and the misuse is kept for
+    /// `take_internal_error`.
+    pub fn set_strict_misuse(&mut self, panic: bool) {
+        self.strict_misuse = panic;
+    }
+
+    fn misuse(&mut self, msg: &str) -> Error {
+        if self.strict_misuse {
+            panic!("{}", msg);
+        }
+        self.set_internal_error(msg.to_string());
+        Error::new(ErrorKind::InvalidInput, msg)
+    }
+
+    /// Keep the first internal inconsistency hit, in place of a panic.
+    fn set_internal_error(&mut self, msg: String) {
+        self.internal_error.get_or_insert(msg);
+    }
+
+    /// The first internal inconsistency the mock recovered from, if any,
+    /// clearing it.
+    pub fn take_internal_error(&mut self) -> Option<String> {
+        self.internal_error.take()
+    }
+
+    /// `read_frame` without framing: everything pending, in one go.
+    fn read_raw(&mut self) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        while self.peek_byte().is_some() {
+            let (len, _) = self.run_len(usize::MAX, false);
+            let start = out.len();
+            out.resize(start + len, 0);
+            self.take_run(&mut out[start..], false);
+        }
+
+        if out.is_empty() {
+            if self.exhausted {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "End of stream"));
+            }
+            return Err(Error::new(ErrorKind::WouldBlock, "No data available"));
+        }
+
+        if self.observed() {
+            self.record(Interaction::Read(out.clone()));
+        }
+        self.read_served();
+
+        Ok(out)
+    }
+
+    /// Append up to `max` available bytes to `ring`, across frame
+    /// boundaries, and return how many were added.
+    pub fn read_into_ring(&mut self, ring: &mut VecDeque<u8>, max: usize) -> Result<usize, Error> {
+        self.check_readable()?;
+        if let Some(fault) = self.take_byte_fault() {
+            return Err(SerialError::from(fault).into());
+        }
+
+        let start = ring.len();
+        ring.reserve(max);
+        while ring.len() - start < max
+            && self.connected
+            && self.byte_faults.keys().next() != Some(&self.delivered)
+        {
+            match self.next_byte() {
+                Some((v, _)) => ring.push_back(v),
+                None => break,
+            }
+        }
+
+        let n = ring.len() - start;
+        if n == 0 && max > 0 {
+            if self.exhausted {
+                return Ok(0);
+            }
+            return Err(Error::new(self.empty_read_kind, "No data available"));
+        }
+
+        if self.observed() {
+            self.record(Interaction::Read(ring.range(start..).copied().collect()));
+        }
+        self.read_served();
+
+        Ok(n)
+    }
+
+    /// Read one complete frame, up to and including the stop byte. Returns
+    /// `WouldBlock` without consuming anything if no complete frame is queued.
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        self.check_readable()?;
+
+        self.release_due_responses();
+        if self.framing == Framing::Raw {
+            return self.read_raw();
+        }
+        if let Some(sync) = self.preamble {
+            while self.peek_byte().is_some_and(|b| b != sync) {
+                self.next_byte();
+                self.discarded += 1;
+            }
+        }
+
+        while !self.has_complete_frame() {
+            match self.next_source_frame() {
+                Some(r) => self.response_queue.push_back(r.into()),
+                None => break,
+            }
+        }
+
+        if !self.has_complete_frame() {
+            // The link drops before the frame ends, hand out what made it
+            if self.drop_after_read || self.queue_iter().any(|r| r.drop_after) {
+                let mut frame = Vec::new();
+                while self.connected {
+                    match self.next_byte() {
+                        Some((v, _)) => frame.push(v),
+                        None => break,
+                    }
+                }
+                if self.observed() {
+                    self.record(Interaction::Read(frame.clone()));
+                }
+                self.read_served();
+                return Ok(frame);
+            }
+            if self.exhausted && self.pending_iter().next().is_none() {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "End of stream"));
+            }
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "No complete frame available",
+            ));
+        }
+
+        let mut frame = Vec::new();
+        if self.escape_byte.is_some() {
+            while let Some((v, ends_frame)) = self.next_byte() {
+                // Escape prefixes are dropped, the byte they protect is kept
+                if !self.escape_pending {
+                    frame.push(v);
+                }
+                if ends_frame {
+                    break;
+                }
+            }
+        } else {
+            while self.peek_byte().is_some() {
+                let (len, ends_frame) = self.run_len(usize::MAX, true);
+                let start = frame.len();
+                frame.resize(start + len, 0);
+                self.take_run(&mut frame[start..], ends_frame);
+
+                if ends_frame {
+                    break;
+                }
+            }
+        }
+
+        if self.framing == Framing::Cobs {
+            frame.pop();
+            frame = match cobs_decode(&frame) {
+                Some(payload) => payload,
+                None => return Err(Error::new(ErrorKind::InvalidData, "Invalid COBS frame")),
+            };
+        }
+        if self.framing == Framing::Slip {
+            frame.pop();
+            frame = match slip_decode(&frame) {
+                Some(payload) => payload,
+                None => return Err(Error::new(ErrorKind::InvalidData, "Invalid SLIP frame")),
+            };
+        }
+
+        if self.delimited.is_some() {
+            if !self.keep_end_delimiter && frame.last() == Some(&self.current_stop()) {
+                frame.pop();
+            }
+            if frame.first() == self.preamble.as_ref() {
+                frame.remove(0);
+            }
+        }
+
+        if let Some(to) = &self.newline_normalize {
+            let newline = if frame.ends_with(b"\r\n") {
+                2
+            } else if frame.ends_with(b"\n") || frame.ends_with(b"\r") {
+                1
+            } else {
+                0
+            };
+            if newline > 0 {
+                frame.truncate(frame.len() - newline);
+                frame.extend_from_slice(to);
+            }
+        }
+
+        if self.observed() {
+            self.record(Interaction::Read(frame.clone()));
+        }
+        self.read_served();
+
+        match &self.read_failure {
+            None => Ok(frame),
+            Some((kind, message)) => Err(Error::new(*kind, message.clone())),
+        }
+    }
+
+    /// Switch the byte frames end with. A frame already partly read still
+    /// ends with the stop byte it started under.
+    pub fn set_stop_byte(&mut self, b: u8) {
+        if self.last_read_index > 0 && self.frame_stop.is_none() {
+            self.frame_stop = Some(self.stop_byte);
+        }
+        // Chosen by hand, so leaving COBS or SLIP keeps it
+        self.framing_stop = None;
+        self.stop_byte = b;
+        self.stop_lead.clear();
+        self.recent.clear();
+    }
+
+    /// End frames on the multi-byte `seq`, such as `b"\r\n"`, instead of a
+    /// single stop byte. Its last byte becomes the stop byte, which ends a
+    /// frame only right after the rest of `seq`, even when those bytes came
+    /// in an earlier read or response. Per-frame terminators still end
+    /// their frame on their own.
+    pub fn set_stop_sequence(&mut self, seq: &[u8]) {
+        let Some((stop, lead)) = seq.split_last() else {
+            self.misuse("set_stop_sequence called with an empty sequence");
+            return;
+        };
+        self.set_stop_byte(*stop);
+        self.stop_lead = lead.to_vec();
+    }
+
+    /// Whether `b` ends a frame stopped by `stop`, given the bytes before
+    /// it in `window`. Moves `window` on past `b`.
+    fn ends_sequence(&self, window: &mut Vec<u8>, b: u8, stop: u8) -> bool {
+        let ends = b == stop && (stop != self.stop_byte || window.ends_with(&self.stop_lead));
+        if ends {
+            window.clear();
+        } else if !self.stop_lead.is_empty() {
+            if window.len() == self.stop_lead.len() {
+                window.remove(0);
+            }
+            window.push(b);
+        }
+        ends
+    }
+
+    pub fn stop_byte(&self) -> u8 {
+        self.stop_byte
+    }
+
+    /// Deliver each response with its bytes in reverse order. A trailing
+    /// stop byte stays at the end so framing still works.
+    pub fn set_byte_reverse(&mut self, enabled: bool) {
+        self.byte_reverse = enabled;
+    }
+
+    /// Deliver each byte with its bits in reverse order, as from an adapter
+    /// sending LSB first. Framing still follows the bytes as sent.
+    pub fn set_bit_reversal(&mut self, enabled: bool) {
+        self.bit_reversal = enabled;
+    }
+
+    /// Emit each delivered byte twice with the given probability, drawn
+    /// from an RNG seeded with `seed`. Only affects `read`.
+    pub fn set_byte_duplication(&mut self, probability: f64, seed: u64) {
+        self.duplication = Some(Duplication {
+            probability,
+            state: seed,
+        });
+    }
+
+    /// Switch framing. `Framing::Cobs` makes the zero byte the stop byte,
+    /// `Framing::Slip` makes it `SLIP_END`; switching away from them puts
+    /// the previous stop byte back, without a stop sequence it was part of.
+    /// Takes effect from the next read; pending bytes are kept, a pending
+    /// escape is dropped.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+        self.escape_pending = false;
+        let stop = match framing {
+            Framing::StopByte | Framing::Raw => {
+                if let Some(b) = self.framing_stop.take() {
+                    self.stop_byte = b;
+                }
+                return;
+            }
+            Framing::Cobs => 0,
+            Framing::Slip => SLIP_END,
+        };
+        let previous = self.stop_byte;
+        self.framing_stop.get_or_insert(previous);
+        self.stop_byte = stop;
+        self.stop_lead.clear();
+        self.recent.clear();
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// `set_framing(Framing::Raw)`.
+    pub fn set_unframed(&mut self) {
+        self.set_framing(Framing::Raw);
+    }
+
+    /// Queue `r` once `trigger_index` frames in total have been read out
+    /// of the port.
+    pub fn add_response_after_read(&mut self, trigger_index: usize, r: &[u8]) {
+        if trigger_index <= self.frames_delivered {
+            self.enqueue(r.to_vec());
+        } else {
+            self.after_read.push((trigger_index, r.to_vec()));
+        }
+    }
+
+    /// Queue `payload` ending in `terminator` rather than the port's stop
+    /// byte. The frame ends at its terminator only.
+    pub fn add_response_with_terminator(&mut self, payload: &[u8], terminator: u8) {
+        let mut data = payload.to_vec();
+        data.push(terminator);
+        self.push_response(Queued {
+            data,
+            terminator: Some(terminator),
+            ..Default::default()
+        });
+    }
+
+    /// Deliver `bytes` before anything queued, like the noise a device
+    /// spews at power-up. It reads as one chunk up to its last byte, stop
+    /// bytes inside it included.
+    pub fn set_startup_garbage(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.push_next(Queued {
+            data: bytes.to_vec(),
+            unsegmented: true,
+            ..Default::default()
+        });
+    }
+
+    /// The byte ending the frame in the read buffer.
+    fn current_stop(&self) -> u8 {
+        self.frame_stop.unwrap_or(self.stop_byte)
+    }
+
+    /// The byte ending queued frame `r`.
+    fn stop_for(&self, r: &Queued) -> u8 {
+        r.terminator.unwrap_or(self.stop_byte)
+    }
+
+    /// Queue `count` frames of seeded random bytes, each with a payload
+    /// length drawn from `len_range` and ended by the stop byte. Payloads
+    /// never contain the stop byte or the escape byte.
+    pub fn fill_random_frames(&mut self, count: usize, len_range: Range<usize>, seed: u64) {
+        let mut state = seed;
+        let span = len_range.end.saturating_sub(len_range.start) as u64;
+
+        for _ in 0..count {
+            let len = match span {
+                0 => len_range.start,
+                span => len_range.start + (splitmix64(&mut state) % span) as usize,
+            };
+            let mut frame = Vec::with_capacity(len + 1);
+            while frame.len() < len {
+                let b = splitmix64(&mut state) as u8;
+                if b != self.stop_byte && Some(b) != self.escape_byte {
+                    frame.push(b);
+                }
+            }
+            frame.push(self.stop_byte);
+            self.push_response(frame);
+        }
+    }
+
+    /// Queue `payload` as `encoding` text, ended by the stop byte.
+    pub fn add_encoded_response(&mut self, payload: &[u8], encoding: Encoding) {
+        let mut frame = encode_text(payload, encoding);
+        frame.push(self.stop_byte);
+        self.add_response(&frame);
+    }
+
+    /// Queue `payload` SLIP-encoded and END-terminated.
+    pub fn add_slip_response(&mut self, payload: &[u8]) {
+        let mut frame = slip_encode(payload);
+        frame.push(SLIP_END);
+        self.add_response(&frame);
+    }
+
+    /// Queue `payload` COBS-encoded and zero-terminated.
+    pub fn add_cobs_response(&mut self, payload: &[u8]) {
+        let mut frame = cobs_encode(payload);
+        frame.push(0);
+        self.add_response(&frame);
+    }
+
+    /// Replace a trailing `\r`, `\n` or `\r\n` on frames from `read_frame`
+    /// with `to`.
+    pub fn set_read_newline_normalize(&mut self, to: Vec<u8>) {
+        self.newline_normalize = Some(to);
+    }
+
+    /// Treat a stop byte preceded by `escape_byte` as data in `read_frame`.
+    pub fn set_escape(&mut self, escape_byte: u8) {
+        self.escape_byte = Some(escape_byte);
+    }
+
+    fn has_complete_frame(&self) -> bool {
+        self.complete_frames(1) > 0
+    }
+
+    /// Count the complete frames pending, stopping once `limit` are found.
+    fn complete_frames(&self, limit: usize) -> usize {
+        let mut escaped = self.escape_pending;
+        let mut frames = 0;
+        let mut window = self.recent.clone();
+        // An unsegmented frame, without a stop byte, ends at its last byte
+        let mut scan = |b: u8, stop: Option<u8>, last: bool| {
+            let ends = match stop {
+                Some(stop) => self.ends_sequence(&mut window, b, stop) && !escaped,
+                None => last,
+            };
+            if ends {
+                frames += 1;
+            }
+            escaped = !escaped && Some(b) == self.escape_byte;
+            frames >= limit
+        };
+
+        // Nothing arrives past the point the link drops
+        let stop = (!self.unsegmented).then(|| self.current_stop());
+        let unread = self
+            .actual_response
+            .len()
+            .saturating_sub(self.last_read_index);
+        if !self
+            .actual_response
+            .range(self.last_read_index..)
+            .enumerate()
+            .any(|(i, b)| scan(*b, stop, i + 1 == unread))
+            && !self.drop_after_read
+        {
+            for r in self.queue_iter() {
+                let stop = (!r.unsegmented).then(|| self.stop_for(r));
+                let last = r.data.len();
+                if r.data
+                    .iter()
+                    .enumerate()
+                    .any(|(i, b)| scan(*b, stop, i + 1 == last))
+                    || r.drop_after
+                {
+                    break;
+                }
+            }
+        }
+
+        frames.min(limit)
+    }
+
+    /// Read exactly `n` complete frames. Returns `UnexpectedEof` without
+    /// consuming anything if fewer are available.
+    pub fn read_n_frames(&mut self, n: usize) -> Result<Vec<Vec<u8>>, Error> {
+        self.release_due_responses();
+        while self.complete_frames(n) < n {
+            match self.next_source_frame() {
+                Some(r) => self.response_queue.push_back(r.into()),
+                None => break,
+            }
+        }
+
+        if self.complete_frames(n) < n {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Fewer frames available than requested",
+            ));
+        }
+
+        (0..n).map(|_| self.read_frame()).collect()
+    }
+
+    /// Make every read from now on fail with `kind` and `message`, after
+    /// taking its data off the line.
+    pub fn fail_reads_with(&mut self, kind: ErrorKind, message: &str) {
+        self.read_failure = Some((kind, message.to_string()));
+    }
+
+    /// Let the next `n` reads succeed and make the one after fail with `kind`,
+    /// replacing any previously scripted outcomes.
+    pub fn fail_after_reads(&mut self, n: usize, kind: ErrorKind) {
+        self.success_queue.clear();
+        for _ in 0..n {
+            self.success_queue.push_back((true, ErrorKind::Other));
+        }
+        self.success_queue.push_back((false, kind));
+    }
+
+    /// The scripted read outcomes not consumed yet, as `(succeeds, kind)`.
+    pub fn pending_results(&self) -> Vec<(bool, ErrorKind)> {
+        self.success_queue.iter().copied().collect()
+    }
+
+    /// Drop every scripted read outcome.
+    pub fn clear_results(&mut self) {
+        self.success_queue.clear();
+    }
+
+    /// Common checks run before any read touches the data.
+    fn check_readable(&mut self) -> Result<(), Error> {
+        self.check_link()?;
+        self.check_quota()?;
+        self.check_flaky()?;
+
+        if self.require_flush && self.unflushed {
+            // Reported once, the next read goes through
+            self.unflushed = false;
+            self.flush_violations += 1;
+            return Err(Error::other("Read before the last write was flushed"));
+        }
+
+        if let Some((kind, message)) = &self.wedged {
+            return Err(Error::new(*kind, message.clone()));
+        }
+
+        if let Some((false, kind)) = self.success_queue.front().copied() {
+            self.success_queue.pop_front();
+            return Err(self.fire(Error::new(kind, "Injected read error")));
+        }
+
+        if let Some(fault) = self.line_faults.pop_front() {
+            return Err(self.fire(SerialError::from(fault).into()));
+        }
+
+        Ok(())
+    }
+
+    /// Count a read that handed out data, using up a scripted success.
+    fn read_served(&mut self) {
+        self.reads_served += 1;
+        if self.success_queue.front().is_some_and(|(ok, _)| *ok) {
+            self.success_queue.pop_front();
+        }
+    }
+
+    /// The state checks of `check_readable`, without consuming any
+    /// scripted failure.
+    fn check_link(&self) -> Result<(), Error> {
+        if !self.connected {
+            return Err(match self.disconnect_behavior {
+                DisconnectBehavior::Eof => {
+                    Error::new(ErrorKind::UnexpectedEof, "Port is disconnected")
+                }
+                DisconnectBehavior::Error(kind) => Error::new(kind, "Port is not open"),
+            });
+        }
+
+        if self.busy {
+            return Err(Error::new(ErrorKind::ResourceBusy, "Port is in use"));
+        }
+
+        #[cfg(feature = "std")]
+        self.check_started()?;
+        #[cfg(feature = "std")]
+        self.check_keepalive()?;
+
+        if self.paused {
+            return Err(Error::new(ErrorKind::WouldBlock, "Data delivery is paused"));
+        }
+
+        if self.xoff {
+            return Err(Error::new(ErrorKind::WouldBlock, "Stopped by XOFF"));
+        }
+
+        if self.hung {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "Device is not responding",
+            ));
+        }
+
+        if self.handshake.is_some() {
+            return Err(Error::new(ErrorKind::WouldBlock, "Waiting for handshake"));
+        }
+
+        Ok(())
+    }
+
+    /// Like `read`, but injected line conditions come back as their own
+    /// `SerialError` variant rather than an `InvalidData` I/O error.
+    pub fn read_detailed(&mut self, buff: &mut [u8]) -> Result<usize, SerialError> {
+        self.check_link()?;
+        if let Some(fault) = self.line_faults.pop_front() {
+            return Err(fault.into());
+        }
+        if let Some(fault) = self.take_byte_fault() {
+            return Err(fault.into());
+        }
+
+        Ok(SerialMock::read(self, buff)?)
+    }
+
+    /// Make `read` report a parity error on the byte at `byte_index`,
+    /// counting every byte delivered so far. Reads stop short of that byte,
+    /// the next one fails, and the byte itself is delivered after that.
+    pub fn inject_parity_error_at(&mut self, byte_index: usize) {
+        self.byte_faults.insert(byte_index, LineFault::Parity);
+    }
+
+    /// Like `inject_parity_error_at`, for a framing (bad stop bit) error.
+    pub fn inject_frame_error_at(&mut self, byte_index: usize) {
+        self.byte_faults.insert(byte_index, LineFault::Framing);
+    }
+
+    /// The fault marked on the next byte, if any, consuming the mark.
+    fn take_byte_fault(&mut self) -> Option<LineFault> {
+        // Marks already passed by `read_frame` no longer apply
+        while self
+            .byte_faults
+            .keys()
+            .next()
+            .is_some_and(|at| *at < self.delivered)
+        {
+            self.byte_faults.pop_first();
+        }
+
+        if self.byte_faults.keys().next() == Some(&self.delivered) && self.peek_byte().is_some() {
+            let fault = self.byte_faults.pop_first().map(|(_, fault)| fault);
+            if let Some(f) = fault {
+                self.fire(SerialError::from(f).into());
+            }
+            return fault;
+        }
+
+        None
+    }
+
+    /// Wedge the device on the first injected read error: every read after
+    /// it fails the same way until `reset`.
+    pub fn set_fatal_on_error(&mut self, enabled: bool) {
+        self.fatal_on_error = enabled;
+    }
+
+    /// Note an injected error firing, wedging the device if asked to.
+    fn fire(&mut self, e: Error) -> Error {
+        self.errors_fired += 1;
+        if self.fatal_on_error {
+            self.wedged = Some((e.kind(), e.to_string()));
+        }
+        e
+    }
+
+    /// Reboot the device as a reset trigger would, clearing a fault from
+    /// `set_fatal_on_error` or a missed keepalive.
+    pub fn reset(&mut self) {
+        self.wedged = None;
+        #[cfg(feature = "std")]
+        if let Some((_, last)) = self.keepalive.as_mut() {
+            *last = self.clock.now();
+        }
+        self.device_reset();
+    }
+
+    /// Fail the next read with a framing error.
+    pub fn inject_framing_error(&mut self) {
+        self.line_faults.push_back(LineFault::Framing);
+    }
+
+    /// Fail the next read with a parity error.
+    pub fn inject_parity_error(&mut self) {
+        self.line_faults.push_back(LineFault::Parity);
+    }
+
+    /// Fail the next read with an overrun error.
+    pub fn inject_overrun(&mut self) {
+        self.line_faults.push_back(LineFault::Overrun);
+    }
+
+    /// Fail the next read with a break condition.
+    pub fn inject_break(&mut self) {
+        self.line_faults.push_back(LineFault::Break);
+    }
+
+    /// Drop the link for one operation out of every `drop_every` reads
+    /// and writes: that one fails with `BrokenPipe` and the next finds
+    /// the port back.
+    pub fn set_flaky_connection(&mut self, drop_every: usize) {
+        self.flaky = (drop_every > 0).then_some((drop_every, 0));
+    }
+
+    /// Fail the operation that `set_flaky_connection` picks to drop.
+    fn check_flaky(&mut self) -> Result<(), Error> {
+        if let Some((every, ops)) = &mut self.flaky {
+            *ops += 1;
+            if *ops % *every == 0 {
+                return Err(Error::new(ErrorKind::BrokenPipe, "Connection dropped"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Refuse further I/O once `set_total_byte_limit` is used up.
+    fn check_quota(&self) -> Result<(), Error> {
+        match self.byte_limit {
+            Some(limit) if self.session_bytes >= limit => Err(Error::new(
+                ErrorKind::QuotaExceeded,
+                "Session byte limit reached",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Stop all reads and writes with `QuotaExceeded` once `limit` bytes
+    /// were read and written in total.
+    pub fn set_total_byte_limit(&mut self, limit: usize) {
+        self.byte_limit = Some(limit);
+    }
+
+    /// Common checks run before any write is accepted.
+    fn check_writable(&mut self) -> Result<(), Error> {
+        if !self.connected {
+            return Err(Error::new(ErrorKind::NotConnected, "Port is not open"));
+        }
+
+        if self.busy {
+            return Err(Error::new(ErrorKind::ResourceBusy, "Port is in use"));
+        }
+
+        if self.read_only {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Port is read-only"));
+        }
+
+        #[cfg(feature = "std")]
+        self.check_started()?;
+        #[cfg(feature = "std")]
+        self.check_keepalive()?;
+        self.check_quota()?;
+        self.check_flaky()?;
+
+        Ok(())
+    }
+
+    /// Make the next `try_open_native` fail.
+    pub fn fail_next_open(&mut self) {
+        self.fail_next_open = true;
+    }
+
+    /// Fallible `open_native`. A failed open also leaves this handle closed.
+    pub fn try_open_native(&mut self) -> Result<MockableSerial, Error> {
+        if self.fail_next_open {
+            self.fail_next_open = false;
+            self.set_closed();
+            return Err(Error::new(ErrorKind::NotFound, "Failed to open port"));
+        }
+
+        Ok(self.open_native())
+    }
+
+    /// Make all reads and writes fail with `NotConnected` until `reconnect`.
+    pub fn set_closed(&mut self) {
+        self.connected = false;
+    }
+
+    pub fn reconnect(&mut self) {
+        self.connected = true;
+    }
+
+    /// Honor `XOFF` and `XON` in written data: after an `XOFF` reads return
+    /// `WouldBlock` until an `XON` is written. Both are kept out of rule
+    /// matching.
+    pub fn set_software_flow_control(&mut self, enabled: bool) {
+        self.software_flow_control = enabled;
+        if !enabled {
+            self.xoff = false;
+        }
+    }
+
+    /// Choose what reads report while disconnected. Writes always fail
+    /// with `NotConnected`.
+    pub fn set_disconnect_behavior(&mut self, b: DisconnectBehavior) {
+        self.disconnect_behavior = b;
+    }
+
+    /// Act as if another process took the port: reads and writes fail with
+    /// `ResourceBusy` until `release`.
+    pub fn simulate_busy(&mut self) {
+        self.busy = true;
+    }
+
+    pub fn release(&mut self) {
+        self.busy = false;
+    }
+
+    /// The device path, as `serialport::SerialPort::name` reports it.
+    pub fn name(&self) -> Option<String> {
+        Some(self.address.clone())
+    }
+
+    /// Report the port as a USB device with these identifiers. An empty
+    /// `serial` means no serial number.
+    pub fn set_usb_info(&mut self, vid: u16, pid: u16, serial: &str) {
+        self.usb_info = Some(UsbPortInfo {
+            vid,
+            pid,
+            serial_number: (!serial.is_empty()).then(|| serial.to_string()),
+        });
+    }
+
+    pub fn usb_info(&self) -> Option<UsbPortInfo> {
+        self.usb_info.clone()
+    }
+
+    /// Move the port to a new path, e.g. when it comes back as another
+    /// device node after a replug.
+    pub fn set_address(&mut self, addr: &str) {
+        self.address = addr.to_string();
+    }
+
+    /// Make `read_frame` drop everything before the `sync` byte that starts
+    /// each frame.
+    pub fn set_preamble(&mut self, sync: u8) {
+        self.preamble = Some(sync);
+    }
+
+    /// Frame data as `start`, payload, `end`, like STX/ETX: `read_frame`
+    /// drops everything before `start` and returns the payload alone, or
+    /// with `end` under `set_keep_end_delimiter`. `clear_delimiters` puts
+    /// the previous preamble and stop byte back.
+    pub fn set_delimiters(&mut self, start: u8, end: u8) {
+        if self.delimited.is_none() {
+            self.delimited = Some((self.preamble, self.stop_byte));
+        }
+        self.preamble = Some(start);
+        self.stop_byte = end;
+    }
+
+    /// Leave the `end` delimiter on frames read under `set_delimiters`.
+    pub fn set_keep_end_delimiter(&mut self, keep: bool) {
+        self.keep_end_delimiter = keep;
+    }
+
+    /// Undo `set_delimiters`.
+    pub fn clear_delimiters(&mut self) {
+        if let Some((preamble, stop_byte)) = self.delimited.take() {
+            self.preamble = preamble;
+            self.stop_byte = stop_byte;
+        }
+    }
+
+    /// Let `sm` answer each write, given the device state to read and update.
+    /// Writes it returns `None` for fall through to the rules. Like a
+    /// generator, the closure is not carried over by `open_native`.
+    pub fn set_state_machine(&mut self, sm: StateMachine) {
+        self.state_machine = Some(sm);
+    }
+
+    /// The state kept for `set_state_machine`.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Produce frames on demand once the queue empties. When `f` returns
+    /// `None` the stream ends: `read` returns `Ok(0)` and `read_frame`
+    /// `UnexpectedEof`. `f` must be `Send` so the port stays `Send`: a
+    /// `SharedSerial` hands it to other threads, and serialport's
+    /// `SerialPort` requires it.
+    pub fn set_generator<F: FnMut() -> Option<Vec<u8>> + Send + 'static>(&mut self, f: F) {
+        self.generator = Some(Box::new(f));
+        self.exhausted = false;
+    }
+
+    /// Stream `pattern` over and over until `total_bytes` have been read,
+    /// then end the stream as a generator does, without storing it.
+    pub fn set_pattern_stream(&mut self, pattern: Vec<u8>, total_bytes: usize) {
+        let mut left = if pattern.is_empty() { 0 } else { total_bytes };
+        self.set_generator(move || {
+            if left == 0 {
+                return None;
+            }
+            let n = left.min(pattern.len());
+            left -= n;
+            Some(pattern[..n].to_vec())
+        });
+    }
+
+    /// Call `f` whenever `read` finds nothing to deliver, before it gives
+    /// up. `f` gets the port, so it can queue more with `add_response`;
+    /// reads made from inside `f` don't call it again. Like a generator,
+    /// the closure is not carried over by `open_native`.
+    pub fn on_empty<F: FnMut(&mut MockableSerial) + Send + 'static>(&mut self, f: F) {
+        self.on_empty = Some(Box::new(f));
+    }
+
+    fn run_on_empty(&mut self) {
+        if let Some(mut f) = self.on_empty.take() {
+            f(self);
+            // `f` may have installed a new hook
+            if self.on_empty.is_none() {
+                self.on_empty = Some(f);
+            }
+        }
+    }
+
+    /// Drop bytes until `sync` is next, leaving it unread, and return how
+    /// many were dropped. Fails with `UnexpectedEof` if the data runs out
+    /// first; what was dropped stays dropped.
+    pub fn skip_to(&mut self, sync: u8) -> Result<usize, Error> {
+        self.check_readable()?;
+
+        let mut skipped = 0;
+        loop {
+            match self.peek_byte() {
+                Some(b) if b == sync => break,
+                Some(_) => {
+                    self.next_byte();
+                    skipped += 1;
+                }
+                None => {
+                    self.discarded += skipped;
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Data ended before the sync byte",
+                    ));
+                }
+            }
+        }
+        self.discarded += skipped;
+
+        Ok(skipped)
+    }
+
+    /// Bytes dropped by `read_frame` while looking for the preamble, or by
+    /// `skip_to`.
+    pub fn discarded_bytes(&self) -> usize {
+        self.discarded
+    }
+
+    fn peek_byte(&mut self) -> Option<u8> {
+        self.release_due_responses();
+        #[cfg(feature = "notify")]
+        self.poll_watched_file();
+
+        // Fetch a new item from the queue if there is nothing to read
+        while self.actual_response.is_empty() {
+            let next = match self.shuffle.as_mut() {
+                Some(state) if !self.response_queue.is_empty() => {
+                    let at = splitmix64(state) % self.response_queue.len() as u64;
+                    self.response_queue.remove(at as usize)
+                }
+                _ => match self.order {
+                    QueueOrder::Fifo => self.response_queue.pop_front(),
+                    QueueOrder::Lifo => self.response_queue.pop_back(),
+                },
+            };
+            let Some(r) = next else {
+                break;
+            };
+            // Frames for another bus address are dropped unread
+            if self.addressed_here(&r) {
+                self.load(r);
+            }
+        }
+        if self.actual_response.is_empty() {
+            if let Some(r) = self.next_source_frame() {
+                self.load(r);
+            }
+        }
+
+        self.actual_response.get(self.last_read_index).copied()
+    }
+
+    fn load(&mut self, r: impl Into<Queued>) {
+        let Queued {
+            data: mut r,
+            terminator,
+            drop_after,
+            unsegmented,
+            target: _,
+        } = r.into();
+        self.frame_stop = terminator;
+        self.drop_after_read = drop_after;
+        self.unsegmented = unsegmented;
+
+        if self.byte_reverse {
+            let end = match r.last() {
+                Some(b) if *b == self.current_stop() => r.len() - 1,
+                _ => r.len(),
+            };
+            r[..end].reverse();
+        }
+
+        self.actual_response.extend(r);
+        // Keeps `consumed_in_frame` a single slice; free when nothing wraps
+        self.actual_response.make_contiguous();
+    }
+
+    /// The bytes of the response being read that were already delivered
+    /// since the last frame boundary.
+    pub fn consumed_in_frame(&self) -> &[u8] {
+        let (front, _) = self.actual_response.as_slices();
+        &front[..self.last_read_index.min(front.len())]
+    }
+
+    /// Pull a frame from the channel or the generator once the queue is dry.
+    fn next_source_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let r = self.pull_source_frame()?;
+            if self.passes_crc(&r) {
+                return Some(r);
+            }
+        }
+    }
+
+    fn pull_source_frame(&mut self) -> Option<Vec<u8>> {
+        #[cfg(feature = "std")]
+        if let Some(r) = self.try_receive() {
+            return Some(r);
+        }
+        #[cfg(feature = "serialport")]
+        if let Some(r) = self.read_passthrough() {
+            return Some(r);
+        }
+
+        let r = (self.generator.as_mut()?)();
+        if r.is_none() {
+            self.generator = None;
+            self.exhausted = true;
+        }
+        r
+    }
+
+    /// Pop the next byte off the stream, along with whether it ends a frame.
+    fn next_byte(&mut self) -> Option<(u8, bool)> {
+        let v = self.peek_byte()?;
+        let ends_frame = if self.unsegmented {
+            self.last_read_index + 1 == self.actual_response.len()
+        } else {
+            let mut window = self.recent.clone();
+            self.ends_sequence(&mut window, v, self.current_stop()) && !self.escape_pending
+        };
+        self.escape_pending = !self.escape_pending && Some(v) == self.escape_byte;
+
+        self.advance(1, ends_frame);
+        if ends_frame {
+            self.count_frames(1);
+        }
+
+        Some((self.garble(v), ends_frame))
+    }
+
+    /// The unread part of the rolling buffer, as the ring's two halves.
+    fn unread(&self) -> (&[u8], &[u8]) {
+        let (front, back) = self.actual_response.as_slices();
+
+        if self.last_read_index <= front.len() {
+            (&front[self.last_read_index..], back)
+        } else {
+            (&[], &back[self.last_read_index - front.len()..])
+        }
+    }
+
+    /// Length of the next run of the current frame that can be copied out in
+    /// one go, capped at `max`, and whether it ends on a stop byte. Only
+    /// valid when no escape byte is configured.
+    fn run_len(&self, max: usize, stop_at_frame: bool) -> (usize, bool) {
+        let (front, back) = self.unread();
+        let len = max.min(front.len() + back.len());
+
+        if self.unsegmented {
+            return (len, len == front.len() + back.len());
+        }
+        if stop_at_frame && !self.stop_lead.is_empty() {
+            let stop = self.current_stop();
+            let mut window = self.recent.clone();
+            let pos = front
+                .iter()
+                .chain(back)
+                .take(len)
+                .position(|b| self.ends_sequence(&mut window, *b, stop));
+            if let Some(pos) = pos {
+                return (pos + 1, true);
+            }
+        } else if stop_at_frame {
+            let in_front = len.min(front.len());
+            let stop = self.current_stop();
+            let found = match front[..in_front].iter().position(|b| *b == stop) {
+                Some(pos) => Some(pos),
+                None => back[..len - in_front]
+                    .iter()
+                    .position(|b| *b == stop)
+                    .map(|pos| front.len() + pos),
+            };
+            if let Some(pos) = found {
+                return (pos + 1, true);
+            }
+        }
+
+        (len, false)
+    }
+
+    /// Copy the next `out.len()` bytes of the buffer into `out`, as measured
+    /// by `run_len`.
+    fn take_run(&mut self, out: &mut [u8], ends_frame: bool) {
+        let (front, back) = self.unread();
+        let in_front = out.len().min(front.len());
+        let in_back = out.len() - in_front;
+        out[..in_front].copy_from_slice(&front[..in_front]);
+        out[in_front..].copy_from_slice(&back[..in_back]);
+
+        let stop = self.current_stop();
+        let mut window = self.recent.clone();
+        let frames = if ends_frame {
+            1
+        } else {
+            out.iter()
+                .filter(|b| self.ends_sequence(&mut window, **b, stop))
+                .count()
+        };
+
+        if self.device_baud.is_some() || self.bit_reversal {
+            for v in out.iter_mut() {
+                *v = self.garble(*v);
+            }
+        }
+        self.advance(out.len(), ends_frame);
+        self.count_frames(frames);
+    }
+
+    /// Note `n` more frames read out and release the responses waiting on
+    /// them.
+    fn count_frames(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        self.frames_delivered += n;
+
+        while let Some(pos) = self
+            .after_read
+            .iter()
+            .position(|(at, _)| *at <= self.frames_delivered)
+        {
+            let (_, r) = self.after_read.remove(pos);
+            self.enqueue(r);
+        }
+    }
+
+    /// Move the cursor `n` bytes forward. A finished frame is dropped from
+    /// the front of the buffer; capacity is kept for the next one.
+    fn advance(&mut self, n: usize, ends_frame: bool) {
+        if !self.stop_lead.is_empty() {
+            let mut window = core::mem::take(&mut self.recent);
+            let stop = self.current_stop();
+            let end = (self.last_read_index + n).min(self.actual_response.len());
+            for b in self.actual_response.range(self.last_read_index..end) {
+                self.ends_sequence(&mut window, *b, stop);
+            }
+            self.recent = window;
+        }
+        self.last_read_index += n;
+        self.delivered += n;
+        self.session_bytes += n;
+
+        if self.last_read_index >= self.actual_response.len() {
+            self.actual_response.clear();
+            self.last_read_index = 0;
+            if self.drop_after_read {
+                self.drop_after_read = false;
+                self.connected = false;
+            }
+        } else if ends_frame {
+            self.actual_response.drain(..self.last_read_index);
+            self.last_read_index = 0;
+        }
+    }
+
+    /// Simulate the device talking at `actual_device_baud`. When it differs
+    /// from the port's baud every delivered byte is XORed with
+    /// `(ratio % 255) + 1`, where `ratio` is the faster baud divided by the
+    /// slower one. Framing still follows the bytes the device really sent.
+    pub fn set_baud_mismatch(&mut self, actual_device_baud: u32) {
+        self.device_baud = Some(actual_device_baud);
+    }
+
+    fn garble(&self, v: u8) -> u8 {
+        let v = if self.bit_reversal {
+            v.reverse_bits()
+        } else {
+            v
+        };
+        match self.device_baud {
+            Some(device) if device != self.baud => {
+                let ratio = device.max(self.baud) / device.min(self.baud).max(1);
+                v ^ ((ratio % 255) + 1) as u8
+            }
+            _ => v,
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn release_due_responses(&mut self) {}
+
+    /// Render all pending response bytes in `hexdump -C` style.
+    pub fn hexdump(&self) -> String {
+        let mut out = String::new();
+
+        for (line, chunk) in self.pending_bytes().chunks(16).enumerate() {
+            out.push_str(&format!("{:08x}  ", line * 16));
+
+            for i in 0..16 {
+                match chunk.get(i) {
+                    Some(b) => out.push_str(&format!("{:02x} ", b)),
+                    None => out.push_str("   "),
+                }
+                if i == 7 {
+                    out.push(' ');
+                }
+            }
+
+            out.push_str(" |");
+            for b in chunk.iter() {
+                if b.is_ascii_graphic() || *b == b' ' {
+                    out.push(*b as char);
+                } else {
+                    out.push('.');
+                }
+            }
+            out.push_str("|\n");
+        }
+
+        out
+    }
+
+    fn pending_bytes(&self) -> Vec<u8> {
+        self.pending_iter().collect()
+    }
+
+    fn pending_iter(&self) -> impl Iterator<Item = u8> + '_ {
+        self.actual_response
+            .range(self.last_read_index..)
+            .chain(self.queue_iter().flat_map(|r| r.data.iter()))
+            .copied()
+    }
+
+    /// The queued frames in the order reads will take them.
+    fn queue_iter(&self) -> impl Iterator<Item = &Queued> + '_ {
+        let (fifo, lifo, shuffled) = match (self.shuffle, self.order) {
+            (Some(mut state), _) => {
+                // Replay the picks `peek_byte` is going to make
+                let mut left: Vec<&Queued> = self.response_queue.iter().collect();
+                let mut picked = Vec::with_capacity(left.len());
+                while !left.is_empty() {
+                    let at = splitmix64(&mut state) % left.len() as u64;
+                    picked.push(left.remove(at as usize));
+                }
+                (None, None, Some(picked))
+            }
+            (None, QueueOrder::Fifo) => (Some(self.response_queue.iter()), None, None),
+            (None, QueueOrder::Lifo) => (None, Some(self.response_queue.iter().rev()), None),
+        };
+
+        fifo.into_iter()
+            .flatten()
+            .chain(lifo.into_iter().flatten())
+            .chain(shuffled.into_iter().flatten())
+            .filter(|r| self.addressed_here(r))
+    }
+
+    /// Number of bytes a read could return right now.
+    pub fn bytes_to_read(&self) -> Result<u32, Error> {
+        let n = self.pending_bytes().len();
+        #[cfg(feature = "std")]
+        let n = n + self.due_delayed_len();
+
+        Ok(n as u32)
+    }
+
+    pub fn boxed(self) -> Box<dyn DynSerial> {
+        Box::new(self)
+    }
+
+    /// Limit how many bytes writes accept before a `flush` is needed.
+    pub fn set_write_capacity(&mut self, bytes: usize) {
+        self.write_capacity = Some(bytes);
+        self.write_remaining = bytes;
+    }
+
+    /// Fail the first read after a write that was not followed by `flush`
+    /// with `Other`.
+    pub fn require_flush_between(&mut self, enabled: bool) {
+        self.require_flush = enabled;
+    }
+
+    /// Reads refused by `require_flush_between`.
+    pub fn flush_violations(&self) -> usize {
+        self.flush_violations
+    }
+
+    /// Queue `ack` after every write, for line protocols that acknowledge
+    /// each line.
+    pub fn set_line_ack(&mut self, ack: &[u8]) {
+        self.line_ack = Some(ack.to_vec());
+    }
+
+    /// With `set_line_ack`, refuse a write with `WouldBlock` while the
+    /// previous acknowledgement, or anything queued before it, is unread.
+    pub fn set_strict_line_ack(&mut self, enabled: bool) {
+        self.strict_line_ack = enabled;
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if let Some(capacity) = self.write_capacity {
+            self.write_remaining = capacity;
+        }
+
+        #[cfg(feature = "std")]
+        self.drain_tx();
+
+        self.unflushed = false;
+        Ok(())
+    }
+}
+
+impl DynSerial for MockableSerial {
+    fn read(&mut self, buff: &mut [u8]) -> Result<usize, Error> {
+        SerialMock::read(self, buff)
+    }
+
+    fn write(&mut self, b: &[u8]) -> Result<usize, Error> {
+        SerialMock::write(self, b)
+    }
+
+    fn bytes_to_read(&self) -> Result<u32, Error> {
+        MockableSerial::bytes_to_read(self)
+    }
+}
+
+/// Ports of every live `MockRegistry`, by registry id and name.
+#[cfg(feature = "serialport")]
+static LISTED_PORTS: Mutex<Vec<(u64, String, serialport::SerialPortInfo)>> = Mutex::new(Vec::new());
+
+#[cfg(feature = "serialport")]
+static NEXT_REGISTRY_ID: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+/// Mock stand-in for `serialport::available_ports`: the ports registered
+/// in any `MockRegistry` still alive, named by their address.
+#[cfg(feature = "serialport")]
+pub fn available_mock_ports() -> Vec<serialport::SerialPortInfo> {
+    LISTED_PORTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, _, info)| info.clone())
+        .collect()
+}
+
+/// Named ports for scenarios with several devices.
+#[cfg_attr(not(feature = "serialport"), derive(Default))]
+pub struct MockRegistry {
+    ports: BTreeMap<String, MockableSerial>,
+    #[cfg(feature = "serialport")]
+    id: u64,
+}
+
+#[cfg(feature = "serialport")]
+impl Default for MockRegistry {
+    fn default() -> Self {
+        Self {
+            ports: BTreeMap::new(),
+            id: NEXT_REGISTRY_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+impl MockRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `port` under `name`, replacing any port already there.
+    pub fn register(&mut self, name: &str, port: MockableSerial) {
+        #[cfg(feature = "serialport")]
+        {
+            let info = serialport::SerialPortInfo {
+                port_name: port.address.clone(),
+                port_type: match port.usb_info() {
+                    Some(usb) => serialport::SerialPortType::UsbPort(serialport::UsbPortInfo {
+                        vid: usb.vid,
+                        pid: usb.pid,
+                        serial_number: usb.serial_number,
+                        manufacturer: None,
+                        product: None,
+                    }),
+                    None => serialport::SerialPortType::Unknown,
+                },
+            };
+            let mut listed = LISTED_PORTS.lock().unwrap();
+            listed.retain(|(id, n, _)| !(*id == self.id && n == name));
+            listed.push((self.id, name.to_string(), info));
+        }
+        self.ports.insert(name.to_string(), port);
+    }
+
+    /// Take the port registered as `name` out of the registry.
+    pub fn remove(&mut self, name: &str) -> Option<MockableSerial> {
+        #[cfg(feature = "serialport")]
+        LISTED_PORTS
+            .lock()
+            .unwrap()
+            .retain(|(id, n, _)| !(*id == self.id && n == name));
+        self.ports.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MockableSerial> {
+        self.ports.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut MockableSerial> {
+        self.ports.get_mut(name)
+    }
+
+    /// Open the port registered as `name`, like `try_open_native`. Fails
+    /// with `NotFound` for an unknown name.
+    pub fn open(&mut self, name: &str) -> Result<MockableSerial, Error> {
+        match self.ports.get_mut(name) {
+            Some(port) => port.try_open_native(),
+            None => Err(Error::new(ErrorKind::NotFound, "No such port")),
+        }
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl Drop for MockRegistry {
+    fn drop(&mut self) {
+        LISTED_PORTS
+            .lock()
+            .unwrap()
+            .retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+/// A `MockableSerial` that can be cloned and used from several threads.
+/// Every call holds the lock only for its own duration, so a read that
+/// finds nothing returns `WouldBlock` instead of blocking a writer.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct SharedSerial {
+    inner: Arc<Mutex<MockableSerial>>,
+    read_done: Arc<Condvar>,
+}
+
+#[cfg(feature = "std")]
+impl SharedSerial {
+    pub fn new(port: MockableSerial) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(port)),
+            read_done: Arc::new(Condvar::new()),
+        }
+    }
+
+    fn read_and_notify(&self, buff: &mut [u8]) -> Result<usize, Error> {
+        let r = SerialMock::read(&mut *self.lock(), buff);
+        self.read_done.notify_all();
+        r
+    }
+
+    /// Block until `bytes_to_read` is 0, failing with `TimedOut` after
+    /// `timeout` of real time. Reads through this wrapper wake the wait
+    /// straight away; reads through `lock` are noticed within 10ms.
+    pub fn wait_until_drained(&self, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut port = self.lock();
+
+        while port.bytes_to_read()? > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "Data was not drained"));
+            }
+            let wait = (deadline - now).min(Duration::from_millis(10));
+            port = self.read_done.wait_timeout(port, wait).unwrap().0;
+        }
+
+        Ok(())
+    }
+
+    pub fn add_response(&self, r: &[u8]) {
+        self.lock().add_response(r);
+    }
+
+    /// Lock the port for anything the shared wrapper does not forward.
+    pub fn lock(&self) -> std::sync::MutexGuard<'_, MockableSerial> {
+        self.inner.lock().unwrap()
+    }
+}
+
+#[cfg(feature = "std")]
+impl DynSerial for SharedSerial {
+    fn read(&mut self, buff: &mut [u8]) -> Result<usize, Error> {
+        self.read_and_notify(buff)
+    }
+
+    fn write(&mut self, b: &[u8]) -> Result<usize, Error> {
+        SerialMock::write(&mut *self.lock(), b)
+    }
+
+    fn bytes_to_read(&self) -> Result<u32, Error> {
+        self.lock().bytes_to_read()
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl MockableSerial {
+    /// Tap a real port: writes are forwarded to `real` as well, and
+    /// whatever `real` has received is read back after the queued
+    /// responses, in place of the generator.
+    pub fn set_passthrough(&mut self, real: Box<dyn serialport::SerialPort>) {
+        self.passthrough = Some(real);
+    }
+
+    fn read_passthrough(&mut self) -> Option<Vec<u8>> {
+        let real = self.passthrough.as_mut()?;
+        let n = real.bytes_to_read().ok()? as usize;
+        if n == 0 {
+            return None;
+        }
+
+        let mut r = vec![0; n];
+        let n = std::io::Read::read(real, &mut r).ok()?;
+        r.truncate(n);
+        (n > 0).then_some(r)
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl std::io::Read for SharedSerial {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_and_notify(buf)
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl std::io::Write for SharedSerial {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        SerialMock::write(&mut *self.lock(), buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+/// Lets a shared mock stand in wherever a `Box<dyn SerialPort>` is
+/// expected. Reads never block, so the timeout is only stored; the
+/// modem control lines always read as asserted.
+#[cfg(feature = "serialport")]
+impl serialport::SerialPort for SharedSerial {
+    fn name(&self) -> Option<String> {
+        self.lock().name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.lock().rx_baud())
+    }
+
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        Ok(match self.lock().data_bits() {
+            5 => serialport::DataBits::Five,
+            6 => serialport::DataBits::Six,
+            7 => serialport::DataBits::Seven,
+            _ => serialport::DataBits::Eight,
+        })
+    }
+
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        Ok(if self.lock().software_flow_control {
+            serialport::FlowControl::Software
+        } else {
+            serialport::FlowControl::None
+        })
+    }
+
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        Ok(match self.lock().parity() {
+            Parity::None => serialport::Parity::None,
+            Parity::Odd => serialport::Parity::Odd,
+            Parity::Even => serialport::Parity::Even,
+        })
+    }
+
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        Ok(match self.lock().stop_bits() {
+            2 => serialport::StopBits::Two,
+            _ => serialport::StopBits::One,
+        })
+    }
+
+    fn timeout(&self) -> Duration {
+        self.lock().port_timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.lock().set_baud_rate(baud_rate);
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, data_bits: serialport::DataBits) -> serialport::Result<()> {
+        self.lock().set_data_bits(data_bits.into());
+        Ok(())
+    }
+
+    fn set_flow_control(
+        &mut self,
+        flow_control: serialport::FlowControl,
+    ) -> serialport::Result<()> {
+        self.lock()
+            .set_software_flow_control(flow_control == serialport::FlowControl::Software);
+        Ok(())
+    }
+
+    fn set_parity(&mut self, parity: serialport::Parity) -> serialport::Result<()> {
+        self.lock().set_parity(match parity {
+            serialport::Parity::None => Parity::None,
+            serialport::Parity::Odd => Parity::Odd,
+            serialport::Parity::Even => Parity::Even,
+        });
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        self.lock().set_stop_bits(stop_bits.into());
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.lock().port_timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.lock().bytes_to_read()?)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        if buffer_to_clear != serialport::ClearBuffer::Output {
+            self.lock().drop_unread();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl MockableSerial {
+    /// Drive every timing feature from `clock` instead of the system clock.
+    /// The startup delay restarts from the new clock's current time.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Arc::new(clock);
+        self.undrifted = None;
+        self.opened_at = self.clock.now();
+    }
+
+    /// Make the clock of the timing features run `ppm` parts per million
+    /// fast, or slow when negative. 0 takes the drift away again.
+    pub fn set_clock_drift(&mut self, ppm: i32) {
+        let base = self.undrifted.take().unwrap_or_else(|| self.clock.clone());
+        if ppm == 0 {
+            self.clock = base;
+            return;
+        }
+
+        self.clock = Arc::new(DriftingClock {
+            inner: base.clone(),
+            origin: base.now(),
+            ppm,
+        });
+        self.undrifted = Some(base);
+    }
+
+    /// Stop the clock where it is: delays, cadences and the startup delay
+    /// only move on with `advance_time`, or when a blocking wait sleeps.
+    pub fn freeze_time(&mut self) {
+        if self.frozen.is_some() {
+            return;
+        }
+
+        let frozen = ManualClock {
+            now: Arc::new(Mutex::new(self.clock.now())),
+        };
+        let previous = core::mem::replace(&mut self.clock, Arc::new(frozen.clone()));
+        self.frozen = Some((frozen, previous));
+    }
+
+    /// Step the frozen clock forward by `d`. Does nothing unless frozen.
+    pub fn advance_time(&mut self, d: Duration) {
+        if let Some((frozen, _)) = &self.frozen {
+            frozen.advance(d);
+        }
+    }
+
+    /// Go back to the clock in use before `freeze_time`.
+    pub fn unfreeze_time(&mut self) {
+        if let Some((_, previous)) = self.frozen.take() {
+            self.clock = previous;
+        }
+    }
+
+    /// Queue a response that only becomes readable once `delay` has elapsed.
+    pub fn add_delayed_response(&mut self, r: &[u8], delay: Duration) {
+        self.add_response_due_at(r, self.clock.now() + delay);
+    }
+
+    /// Queue a response that stalls mid-frame: `head` is readable now and
+    /// `tail`, which should end with the stop byte, `gap` later.
+    pub fn add_split_delayed_response(&mut self, head: &[u8], tail: &[u8], gap: Duration) {
+        self.add_response(head);
+        self.add_delayed_response(tail, gap);
+    }
+
+    /// Queue a response that becomes readable once the clock reaches `due`.
+    pub fn add_response_due_at(&mut self, r: &[u8], due: Instant) {
+        let pos = self
+            .delayed_queue
+            .iter()
+            .position(|(at, _)| *at > due)
+            .unwrap_or(self.delayed_queue.len());
+
+        self.delayed_queue.insert(pos, (due, r.to_vec().into()));
+    }
+
+    /// Make each frame from `add_response` become readable after
+    /// `base ± spread`, drawn from an RNG seeded with `seed`.
+    pub fn set_jitter(&mut self, base: Duration, spread: Duration, seed: u64) {
+        self.jitter = Some(Jitter {
+            base,
+            spread,
+            state: seed,
+        });
+    }
+
+    /// Make each frame from `add_response` become readable `per_byte` times
+    /// its length after it is added, on top of any jitter.
+    pub fn set_length_proportional_latency(&mut self, per_byte: Duration) {
+        self.per_byte_latency = Some(per_byte);
+    }
+
+    /// Queue `frames` so they become readable one `gap` apart, the first one
+    /// straight away.
+    pub fn add_burst(&mut self, frames: Vec<Vec<u8>>, gap: Duration) {
+        for (i, frame) in frames.iter().enumerate() {
+            self.add_delayed_response(frame, gap * i as u32);
+        }
+    }
+
+    /// Read like `read`, but wait up to `timeout` for data to become available.
+    pub fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let deadline = self.clock.now() + timeout;
+
+        loop {
+            match SerialMock::read(self, buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            let now = self.clock.now();
+            if now >= deadline {
+                self.timeouts += 1;
+                return Err(Error::new(ErrorKind::TimedOut, "Read timed out"));
+            }
+
+            let wake = match self.delayed_queue.front() {
+                Some((due, _)) => (*due).min(deadline),
+                None => deadline,
+            };
+            self.clock.sleep(wake.saturating_duration_since(now));
+        }
+    }
+
+    /// Wait up to `timeout` until at least `n` bytes are pending, like
+    /// `VMIN`, then read at least that many into `buf`.
+    pub fn read_at_least(
+        &mut self,
+        n: usize,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        if n > buf.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Threshold is larger than the buffer",
+            ));
+        }
+        let deadline = self.clock.now() + timeout;
+
+        loop {
+            self.release_due_responses();
+            if self.pending_iter().count() >= n {
+                break;
+            }
+
+            let now = self.clock.now();
+            if now >= deadline {
+                self.timeouts += 1;
+                return Err(Error::new(ErrorKind::TimedOut, "Read timed out"));
+            }
+
+            let wake = match self.delayed_queue.front() {
+                Some((due, _)) => (*due).min(deadline),
+                None => deadline,
+            };
+            self.clock.sleep(wake.saturating_duration_since(now));
+        }
+
+        let mut got = 0;
+        while got < n {
+            match SerialMock::read(self, &mut buf[got..])? {
+                0 => break,
+                read => got += read,
+            }
+        }
+
+        Ok(got)
+    }
+
+    /// Keep the device booting for `d` after the port is built or opened:
+    /// reads and writes return `WouldBlock` until then.
+    pub fn set_startup_delay(&mut self, d: Duration) {
+        self.startup_delay = Some(d);
+    }
+
+    /// Time since the port was built or opened, or since `set_clock`.
+    pub fn uptime(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.opened_at)
+    }
+
+    /// Make the device reboot every `interval`, starting now: each reboot
+    /// throws away everything unread or still in flight and sends `banner`.
+    pub fn set_bootloop(&mut self, banner: &[u8], interval: Duration) {
+        self.bootloop = Some((banner.to_vec(), interval, self.clock.now()));
+    }
+
+    fn run_bootloop(&mut self) {
+        let Some((banner, interval, next)) = &mut self.bootloop else {
+            return;
+        };
+
+        let now = self.clock.now();
+        if *next > now {
+            return;
+        }
+        while *next <= now && !interval.is_zero() {
+            *next += *interval;
+        }
+        let banner = banner.clone();
+
+        self.drop_unread();
+        self.delayed_queue.clear();
+        self.enqueue(banner);
+    }
+
+    /// Two handles on the device that capture writes together, see
+    /// `shared_writes`, but each read their own copy of the queue.
+    pub fn split(&self) -> (MockableSerial, MockableSerial) {
+        let mut a = self.open_native();
+        a.shared_writes = Some(Arc::new(Mutex::new(Vec::new())));
+        let b = a.open_native();
+        (a, b)
+    }
+
+    /// The writes of every handle from the same `split`, in order. Without
+    /// a split, the writes of this handle.
+    pub fn shared_writes(&self) -> Vec<Vec<u8>> {
+        match &self.shared_writes {
+            Some(shared) => shared.lock().unwrap().clone(),
+            None => self.written.clone(),
+        }
+    }
+
+    /// When each write in `written` happened, as `uptime` at the time.
+    pub fn write_timestamps(&self) -> Vec<Duration> {
+        self.write_times.clone()
+    }
+
+    /// Refuse a write coming less than `d` after the previous one with
+    /// `WouldBlock`, or drop it if `set_drop_fast_writes` is on.
+    pub fn set_min_write_spacing(&mut self, d: Duration) {
+        self.min_write_spacing = Some(d);
+    }
+
+    /// Accept writes that come too fast and throw them away, like a device
+    /// that misses them.
+    pub fn set_drop_fast_writes(&mut self, enabled: bool) {
+        self.drop_fast_writes = enabled;
+    }
+
+    /// Writes refused or dropped by `set_min_write_spacing`.
+    pub fn dropped_writes(&self) -> usize {
+        self.dropped_writes
+    }
+
+    fn write_too_soon(&self) -> bool {
+        match (self.min_write_spacing, self.write_times.last()) {
+            (Some(d), Some(last)) => self.uptime().saturating_sub(*last) < d,
+            _ => false,
+        }
+    }
+
+    /// Drop the link when no write comes within `within` of the previous
+    /// one, or of this call: reads and writes then fail with `NotConnected`
+    /// until `reset`.
+    pub fn set_keepalive(&mut self, within: Duration) {
+        self.keepalive = Some((within, self.clock.now()));
+    }
+
+    fn check_keepalive(&self) -> Result<(), Error> {
+        match self.keepalive {
+            Some((within, last)) if self.clock.now().saturating_duration_since(last) > within => {
+                Err(Error::new(ErrorKind::NotConnected, "Keepalive missed"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_started(&self) -> Result<(), Error> {
+        match self.startup_delay {
+            Some(d) if self.clock.now() < self.opened_at + d => {
+                Err(Error::new(ErrorKind::WouldBlock, "Device is starting up"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Copy every read and write to `sink`, one line per call, as `RX` or
+    /// `TX` followed by the bytes in hex.
+    pub fn set_tee<W: std::io::Write + Send + 'static>(&mut self, sink: W) {
+        self.tee = Some(Arc::new(Mutex::new(sink)));
+    }
+
+    /// Like `add_rule`, but the response only becomes readable `delay` after
+    /// the matching write.
+    pub fn add_rule_with_delay(&mut self, request: &[u8], response: &[u8], delay: Duration) {
+        self.rules.push(Rule {
+            request: request.to_vec(),
+            response: response.to_vec(),
+            delay: Some(delay),
+            ..Default::default()
+        });
+    }
+
+    /// Delay each rule response by the next of `samples`, cycling through
+    /// them.
+    pub fn set_latency_profile(&mut self, samples: Vec<Duration>) {
+        self.latency_profile = samples;
+        self.latency_index = 0;
+    }
+
+    /// Wait up to `timeout` for a complete frame. Running out of time is
+    /// `Ok(None)`, not an error; any other failure is passed on.
+    pub fn read_frame_or_timeout(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        let deadline = self.clock.now() + timeout;
+
+        loop {
+            match self.read_frame() {
+                Ok(frame) => return Ok(Some(frame)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            let now = self.clock.now();
+            if now >= deadline {
+                self.timeouts += 1;
+                return Ok(None);
+            }
+
+            let wake = match self.delayed_queue.front() {
+                Some((due, _)) => (*due).min(deadline),
+                None => deadline,
+            };
+            self.clock.sleep(wake.saturating_duration_since(now));
+        }
+    }
+
+    /// Read like `read`, but wait for data until it arrives or `cancel` is
+    /// set, in which case the read fails with `Interrupted`.
+    pub fn read_cancellable(
+        &mut self,
+        buf: &mut [u8],
+        cancel: &std::sync::atomic::AtomicBool,
+    ) -> Result<usize, Error> {
+        let poll = Duration::from_millis(1);
+
+        loop {
+            if cancel.load(std::sync::atomic::Ordering::Acquire) {
+                return Err(Error::new(ErrorKind::Interrupted, "Read cancelled"));
+            }
+
+            match SerialMock::read(self, buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                r => return r,
+            }
+
+            let now = self.clock.now();
+            let wait = match self.delayed_queue.front() {
+                Some((due, _)) => due.saturating_duration_since(now).min(poll),
+                None => poll,
+            };
+            self.clock.sleep(wait);
+        }
+    }
+
+    /// Like `enable_recording`, but also stamp each interaction with the time
+    /// elapsed since this call.
+    pub fn enable_timed_recording(&mut self) {
+        self.recording_enabled = true;
+        self.recording_started = Some(self.clock.now());
+    }
+
+    /// Like `enable_recording`, but stop recording once `d` has passed,
+    /// keeping what was captured until then.
+    pub fn enable_recording_for(&mut self, d: Duration) {
+        self.recording_enabled = true;
+        self.recording_until = Some(self.clock.now() + d);
+    }
+
+    pub fn timed_recording(&self) -> &[(Duration, Interaction)] {
+        &self.timed_recording
+    }
+
+    /// Build a port that pulls a new frame from `rx` whenever its own queue
+    /// runs dry. Frames not sent yet make reads return `WouldBlock`.
+    pub fn from_receiver(
+        rx: Receiver<Vec<u8>>,
+        address: &str,
+        baud: u32,
+        stop_byte: u8,
+        read_n_bytes: u32,
+    ) -> MockableSerial {
+        let mut m = MockableSerial::new(address, baud, stop_byte, read_n_bytes);
+        m.receiver = Some(Arc::new(Mutex::new(rx)));
+        m
+    }
+
+    fn try_receive(&self) -> Option<Vec<u8>> {
+        self.receiver.as_ref()?.lock().unwrap().try_recv().ok()
+    }
+
+    /// Build a port that replays a raw capture file (as saved by `minicom`
+    /// or `screen`), split into one response per `stop_byte`-terminated
+    /// frame. A trailing unterminated chunk becomes the last response.
+    pub fn from_capture_log(
+        path: &std::path::Path,
+        stop_byte: u8,
+    ) -> Result<MockableSerial, Error> {
+        let data = std::fs::read(path)?;
+        let mut m = MockableSerial::new(DEFAULT_ADDRESS, DEFAULT_BAUD, stop_byte, 1);
+
+        for frame in data.split_inclusive(|b| *b == stop_byte) {
+            m.response_queue.push_back(frame.to_vec().into());
+        }
+
+        Ok(m)
+    }
+
+    /// Make operations take as long as they would on the wire at the
+    /// configured baud and character format, see `frame_transmission_time`.
+    pub fn set_timing_simulation(&mut self, enabled: bool) {
+        self.timing_simulation = enabled;
+    }
+
+    /// Cap writes at `bytes_per_sec`: each `write` returns only once its
+    /// bytes would have gone out at that rate.
+    pub fn set_write_rate(&mut self, bytes_per_sec: u32) {
+        self.write_rate = Some(bytes_per_sec);
+    }
+
+    /// Time to shift `frame_len` bytes out at the configured baud, counting
+    /// a start bit plus the data, parity and stop bits of each byte.
+    pub fn frame_transmission_time(&self, frame_len: usize) -> Duration {
+        self.transmission_time(self.baud, frame_len)
+    }
+
+    fn transmission_time(&self, baud: u32, frame_len: usize) -> Duration {
+        if baud == 0 {
+            return Duration::ZERO;
+        }
+
+        let bits = frame_len as u64 * self.bits_per_byte() as u64;
+        Duration::from_nanos(bits * 1_000_000_000 / baud as u64)
+    }
+
+    /// Bits on the wire per byte: start, data, parity and stop.
+    fn bits_per_byte(&self) -> u32 {
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd | Parity::Even => 1,
+        };
+
+        1 + self.data_bits as u32 + parity + self.stop_bits as u32
+    }
+
+    /// Block until the bytes written since the last flush have been shifted out.
+    fn drain_tx(&mut self) {
+        if self.timing_simulation {
+            self.clock
+                .sleep(self.transmission_time(self.tx_baud(), self.tx_pending));
+        }
+
+        self.tx_pending = 0;
+    }
+
+    fn due_delayed_len(&self) -> usize {
+        let now = self.clock.now();
+
+        self.delayed_queue
+            .iter()
+            .take_while(|(due, _)| *due <= now)
+            .map(|(_, r)| r.data.len())
+            .sum()
+    }
+
+    fn release_due_responses(&mut self) {
+        self.run_bootloop();
+        if self.delayed_queue.is_empty() {
+            return;
+        }
+
+        let now = self.clock.now();
+        while let Some((due, _)) = self.delayed_queue.front() {
+            if *due > now {
+                break;
+            }
+            let (_, r) = self.delayed_queue.pop_front().unwrap();
+            self.enqueue(r);
+        }
+    }
+}
+
+/// A responses file followed by `watch_responses_file`.
+#[cfg(feature = "notify")]
+struct ResponseWatch {
+    path: std::path::PathBuf,
+    loaded: usize,
+    changed: Arc<std::sync::atomic::AtomicBool>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+#[cfg(feature = "notify")]
+impl MockableSerial {
+    /// Queue the frames in `path`, one per line as hex bytes (whitespace
+    /// between bytes optional, blank lines skipped), then keep watching
+    /// it: frames appended later are queued as the file changes.
+    pub fn watch_responses_file(&mut self, path: &std::path::Path) -> Result<(), Error> {
+        use notify::Watcher;
+
+        let changed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = changed.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            if res.is_ok() {
+                flag.store(true, std::sync::atomic::Ordering::Release);
+            }
+        })
+        .map_err(|e| Error::other(e.to_string()))?;
+        watcher
+            .watch(path, notify::RecursiveMode::NonRecursive)
+            .map_err(|e| Error::other(e.to_string()))?;
+
+        self.watch = Some(ResponseWatch {
+            path: path.to_path_buf(),
+            loaded: 0,
+            changed,
+            _watcher: watcher,
+        });
+        self.reload_responses_file()
+    }
+
+    /// Re-read the watched file now and queue any frames not seen yet.
+    pub fn reload_responses_file(&mut self) -> Result<(), Error> {
+        let Some(watch) = &self.watch else {
+            return Ok(());
+        };
+
+        let text = std::fs::read_to_string(&watch.path)?;
+        let mut frames = Vec::new();
+        for line in text.lines() {
+            let hex: String = line.split_whitespace().collect();
+            if hex.is_empty() {
+                continue;
+            }
+            let frame: Option<Vec<u8>> = if hex.is_ascii() && hex.len() % 2 == 0 {
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+                    .collect()
+            } else {
+                None
+            };
+            match frame {
+                Some(frame) => frames.push(frame),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Bad hex line {:?}", line),
+                    ))
+                }
+            }
+        }
+
+        let loaded = watch.loaded.min(frames.len());
+        for frame in frames[loaded..].iter() {
+            self.add_response(frame);
+        }
+        if let Some(watch) = self.watch.as_mut() {
+            watch.loaded = frames.len();
+        }
+
+        Ok(())
+    }
+
+    fn poll_watched_file(&mut self) {
+        let changed = match &self.watch {
+            Some(watch) => watch
+                .changed
+                .swap(false, std::sync::atomic::Ordering::AcqRel),
+            None => false,
+        };
+
+        if changed {
+            // A half-written file is picked up again on the next change
+            let _ = self.reload_responses_file();
+        }
+    }
+}
+
+#[cfg(feature = "regex")]
+impl MockableSerial {
+    /// Read the next frame and panic unless all of it matches `pattern`,
+    /// showing the frame that came instead.
+    pub fn assert_next_frame_matches(&mut self, pattern: &str) -> Result<Vec<u8>, Error> {
+        let re = regex::bytes::Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+
+        let frame = self.read_frame()?;
+        assert!(
+            re.is_match(&frame),
+            "frame {:?} does not match {:?}",
+            String::from_utf8_lossy(&frame),
+            pattern
+        );
+        Ok(frame)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl MockableSerial {
+    /// `read_frame`, returning the frame as `Bytes`.
+    pub fn read_bytes(&mut self) -> Result<bytes::Bytes, Error> {
+        self.read_frame().map(bytes::Bytes::from)
+    }
+
+    /// `add_response` without copying when `b` is the only handle to its data.
+    pub fn add_response_bytes(&mut self, b: bytes::Bytes) {
+        self.push_response(Vec::from(b));
+    }
+}
+
+/// The bytes of an unfinished frame, carried by the `TimedOut` error of
+/// `read_frame_timeout` once `set_return_partial_on_timeout` is on.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialFrame(pub Vec<u8>);
+
+#[cfg(feature = "tokio")]
+impl core::fmt::Display for PartialFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Partial frame of {} bytes within timeout", self.0.len())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl core::error::Error for PartialFrame {}
+
+#[cfg(feature = "tokio")]
+impl MockableSerial {
+    /// Hand out the bytes of a frame that was still incomplete when
+    /// `read_frame_timeout` ran out, as a `PartialFrame` inside the error.
+    pub fn set_return_partial_on_timeout(&mut self, enabled: bool) {
+        self.return_partial = enabled;
+    }
+
+    /// Wait for a complete frame on the tokio clock, failing with `TimedOut`
+    /// if none arrives within `timeout`.
+    pub async fn read_frame_timeout(&mut self, timeout: Duration) -> Result<Vec<u8>, Error> {
+        let wait = async {
+            loop {
+                match self.read_frame() {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    r => return r,
+                }
+
+                match self.delayed_queue.front() {
+                    Some((due, _)) => {
+                        tokio::time::sleep_until(tokio::time::Instant::from_std(*due)).await
+                    }
+                    None => core::future::pending().await,
+                }
+            }
+        };
+
+        let r = tokio::time::timeout(timeout, wait).await;
+        if r.is_err() {
+            self.timeouts += 1;
+        }
+        match r {
+            Ok(r) => r,
+            Err(_) if self.return_partial => {
+                self.release_due_responses();
+                let mut frame = Vec::new();
+                while let Some((v, _)) = self.next_byte() {
+                    frame.push(v);
+                }
+                if self.observed() {
+                    self.record(Interaction::Read(frame.clone()));
+                }
+                Err(Error::new(ErrorKind::TimedOut, PartialFrame(frame)))
+            }
+            Err(_) => Err(Error::new(ErrorKind::TimedOut, "No frame within timeout")),
+        }
+    }
+}
+
+/// Reads that find no data sleep until the next delayed response is due.
+/// Otherwise they ask to be polled again straight away; the mock has no
+/// way to be woken when a response is queued.
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for MockableSerial {
+    fn poll_read(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> core::task::Poll<Result<(), Error>> {
+        let this = self.get_mut();
+
+        match SerialMock::read(this, buf.initialize_unfilled()) {
+            Ok(n) => {
+                buf.advance(n);
+                core::task::Poll::Ready(Ok(()))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                let Some((due, _)) = this.delayed_queue.front() else {
+                    cx.waker().wake_by_ref();
+                    return core::task::Poll::Pending;
+                };
+                let due = tokio::time::Instant::from_std(*due);
+                let timer = this
+                    .read_timer
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(due)));
+                if timer.deadline() != due {
+                    timer.as_mut().reset(due);
+                }
+                // Already due but held back, by a pause for one
+                if core::future::Future::poll(timer.as_mut(), cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+                core::task::Poll::Pending
+            }
+            Err(e) => core::task::Poll::Ready(Err(e)),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for MockableSerial {
+    fn poll_write(
+        self: core::pin::Pin<&mut Self>,
+        _cx: &mut core::task::Context<'_>,
+        buf: &[u8],
+    ) -> core::task::Poll<Result<usize, Error>> {
+        core::task::Poll::Ready(SerialMock::write(self.get_mut(), buf))
+    }
+
+    fn poll_flush(
+        self: core::pin::Pin<&mut Self>,
+        _cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Result<(), Error>> {
+        core::task::Poll::Ready(self.get_mut().flush())
+    }
+
+    fn poll_shutdown(
+        self: core::pin::Pin<&mut Self>,
+        _cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Result<(), Error>> {
+        core::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "codec")]
+impl MockableSerial {
+    /// Run `decoder` over the port's byte stream.
+    pub fn into_framed<D: tokio_util::codec::Decoder>(
+        self,
+        decoder: D,
+    ) -> tokio_util::codec::Framed<MockableSerial, D> {
+        tokio_util::codec::Framed::new(self, decoder)
+    }
+}
+
+/// Iterator over single bytes, see `MockableSerial::bytes`.
+pub struct ByteIter<'a> {
+    port: &'a mut MockableSerial,
+}
+
+impl Iterator for ByteIter<'_> {
+    type Item = Result<u8, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut b = [0];
+
+        match SerialMock::read(self.port, &mut b) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(b[0])),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// What `to_scenario_json` writes out, with all bytes in hex.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Scenario {
+    address: String,
+    baud: u32,
+    stop_byte: u8,
+    read_n_bytes: u32,
+    responses: Vec<String>,
+    rules: Vec<ScenarioRule>,
+    prefix_rules: Vec<(String, String)>,
+    echo_rules: Vec<(String, String)>,
+    /// Request, responses and the index of the next one.
+    cycling_rules: Vec<(String, Vec<String>, usize)>,
+    /// Request, choices and the generator state.
+    random_rules: Vec<(String, Vec<String>, u64)>,
+}
+
+/// An exact rule as `to_scenario_json` writes it out.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ScenarioRule {
+    request: String,
+    response: String,
+    min_version: Option<String>,
+    delay: Option<Duration>,
+}
+
+#[cfg(feature = "serde")]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(feature = "serde")]
+fn from_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Bad hex {:?}", hex),
+        ));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Bad hex {:?}", hex)))
+        })
+        .collect()
+}
+
+#[cfg(feature = "serde")]
+impl MockableSerial {
+    /// Dump the port settings, the frames still queued and the rules as
+    /// JSON, to rebuild the same setup with `from_scenario_json`.
+    ///
+    /// Only the address, baud rate, stop byte and read size are kept of
+    /// the settings. Queued frames come back as plain responses: their
+    /// own terminators, addresses and drop or unsegmented flags are lost.
+    pub fn to_scenario_json(&self) -> String {
+        let pairs = |rules: &[(Vec<u8>, Vec<u8>)]| {
+            rules
+                .iter()
+                .map(|(req, resp)| (to_hex(req), to_hex(resp)))
+                .collect()
+        };
+        let lists = |list: &[Vec<u8>]| list.iter().map(|r| to_hex(r)).collect::<Vec<_>>();
+        let scenario = Scenario {
+            address: self.address.clone(),
+            baud: self.baud,
+            stop_byte: self.stop_byte,
+            read_n_bytes: self.read_n_bytes,
+            responses: self.queued_order().iter().map(|r| to_hex(r)).collect(),
+            rules: self
+                .rules
+                .iter()
+                .map(|r| ScenarioRule {
+                    request: to_hex(&r.request),
+                    response: to_hex(&r.response),
+                    min_version: r.min_version.clone(),
+                    delay: r.delay,
+                })
+                .collect(),
+            prefix_rules: pairs(&self.prefix_rules),
+            echo_rules: pairs(&self.echo_rules),
+            cycling_rules: self
+                .cycling_rules
+                .iter()
+                .map(|(req, responses, next)| (to_hex(req), lists(responses), *next))
+                .collect(),
+            random_rules: self
+                .random_rules
+                .iter()
+                .map(|(req, choices, state)| (to_hex(req), lists(choices), *state))
+                .collect(),
+        };
+        serde_json::to_string(&scenario).unwrap()
+    }
+
+    /// Build a port from the output of `to_scenario_json`.
+    pub fn from_scenario_json(json: &str) -> Result<Self, Error> {
+        let scenario: Scenario = serde_json::from_str(json)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+        let mut port = MockableSerial::new(
+            &scenario.address,
+            scenario.baud,
+            scenario.stop_byte,
+            scenario.read_n_bytes,
+        );
+        for r in &scenario.responses {
+            port.add_response(&from_hex(r)?);
+        }
+        for r in &scenario.rules {
+            port.rules.push(Rule {
+                request: from_hex(&r.request)?,
+                response: from_hex(&r.response)?,
+                min_version: r.min_version.clone(),
+                delay: r.delay,
+            });
+        }
+        for (prefix, resp) in &scenario.prefix_rules {
+            port.add_prefix_rule(&from_hex(prefix)?, &from_hex(resp)?);
+        }
+        for (req, resp) in &scenario.echo_rules {
+            port.add_echo_rule(&from_hex(req)?, &from_hex(resp)?);
+        }
+        let lists = |list: &[String]| {
+            list.iter()
+                .map(|r| from_hex(r))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        for (req, responses, next) in &scenario.cycling_rules {
+            port.cycling_rules
+                .push((from_hex(req)?, lists(responses)?, *next));
+        }
+        for (req, choices, state) in &scenario.random_rules {
+            port.random_rules
+                .push((from_hex(req)?, lists(choices)?, *state));
+        }
+
+        Ok(port)
+    }
+}
+
+/// Build a port on `DEFAULT_ADDRESS` at `DEFAULT_BAUD`, stopping on
+/// `DEFAULT_STOP_BYTE` and reading one byte at a time, with `r` as its only
+/// queued response.
+impl From<Vec<u8>> for MockableSerial {
+    fn from(r: Vec<u8>) -> Self {
+        let mut m = MockableSerial::new(DEFAULT_ADDRESS, DEFAULT_BAUD, DEFAULT_STOP_BYTE, 1);
+        m.response_queue.push_back(r.into());
+        m
+    }
+}
+
+/// Same defaults as `From<Vec<u8>>`.
+impl From<&[u8]> for MockableSerial {
+    fn from(r: &[u8]) -> Self {
+        MockableSerial::from(r.to_vec())
+    }
+}
+
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        cobs_decode, cobs_encode, crc16, decode_frame, recordings_equal, slip_decode, slip_encode,
+        verify_crc16, Contains, DisconnectBehavior, Encoding, ErrorKind, Framing, Interaction,
+        InteractionKind, MockRegistry, MockableSerial, MockableSerialBuilder, QueueOrder,
+        RuleStrategy, SerialConfig, SerialError, SerialMock, StartsWith, UsbPortInfo, XOFF, XON,
+    };
+    use alloc::boxed::Box;
+    use alloc::collections::VecDeque;
+    use alloc::string::ToString;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    #[cfg(feature = "std")]
+    use std::time::Duration;
+
+    fn read_resp(p: &mut MockableSerial) -> Vec<u8> {
+        let mut final_buffer = Vec::new();
+
+        loop {
+            let mut read_buf = [0; 1];
+
+            match p.read(read_buf.as_mut_slice()) {
+                Ok(_) => {
+                    let byte = read_buf[0];
+
+                    final_buffer.push(byte);
+
+                    if byte == 0x23_u8 {
+                        break;
+                    }
+                }
+                Err(e) => panic!("Unknown error occurred: {:?}", e),
+            }
+        }
+
+        final_buffer
+    }
+
+    #[test]
     fn test_init() {
         let m = MockableSerialBuilder::new("/dev/null", 115200, 0x35, 1, None);
         let port = m.open_native();
 
-        assert_eq!(port.address, "/dev/null");
-        assert_eq!(port.baud, 115200);
-        assert_eq!(port.stop_byte, 0x35);
-        assert_eq!(port.read_n_bytes, 1);
+        assert_eq!(port.address, "/dev/null");
+        assert_eq!(port.baud, 115200);
+        assert_eq!(port.stop_byte, 0x35);
+        assert_eq!(port.read_n_bytes, 1);
+    }
+
+    #[test]
+    fn test_add_response() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x35, 1, None);
+        let mut port = m.open_native();
+        port.add_response(&[0x65, 0x65, 0x65]);
+
+        assert_eq!(port.response_queue[0].data, vec![0x65, 0x65, 0x65]);
+    }
+
+    #[test]
+    fn test_read() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.add_response(&[
+            0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x77, 0x6f, 0x72, 0x6c, 0x64, 0x23,
+        ]);
+        let final_buffer = read_resp(&mut port);
+        assert_eq!(std::str::from_utf8(&final_buffer).unwrap(), "hello world#");
+    }
+
+    #[test]
+    fn test_initial_response() {
+        let init_resp = VecDeque::from([vec![0x65, 0x65, 0x65], vec![0x64, 0x64, 0x64]]);
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x35, 1, Some(init_resp));
+        let port = m.open_native();
+
+        assert_eq!(port.response_queue[0].data, vec![0x65, 0x65, 0x65]);
+        assert_eq!(port.response_queue[1].data, vec![0x64, 0x64, 0x64]);
+    }
+
+    #[test]
+    fn test_multiple_responses() {
+        let init_resp = VecDeque::from([
+            vec![0x74, 0x65, 0x73, 0x74, 0x31, 0x23],
+            vec![0x74, 0x65, 0x73, 0x74, 0x32, 0x23],
+        ]);
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, Some(init_resp));
+        let mut port = m.open_native();
+
+        let resp1 = read_resp(&mut port);
+        let resp2 = read_resp(&mut port);
+
+        assert_eq!(std::str::from_utf8(&resp1).unwrap(), "test1#");
+        assert_eq!(std::str::from_utf8(&resp2).unwrap(), "test2#");
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.add_response(&[0x6f, 0x6b, 0x23]);
+        port.pause();
+
+        let mut read_buf = [0; 1];
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        port.resume();
+        let final_buffer = read_resp(&mut port);
+        assert_eq!(std::str::from_utf8(&final_buffer).unwrap(), "ok#");
+    }
+
+    #[test]
+    fn test_write_capacity() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.set_write_capacity(4);
+
+        let data = [0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39];
+        assert_eq!(port.write(&data).unwrap(), 4);
+        assert_eq!(port.write(&data[4..]).unwrap(), 0);
+
+        port.flush().unwrap();
+        assert_eq!(port.write(&data[4..]).unwrap(), 4);
+        port.flush().unwrap();
+        assert_eq!(port.write(&data[8..]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.add_response(b"hello world#");
+        port.add_response(&[0x00, 0x01, 0x02, 0x03, 0x04, 0x23]);
+
+        assert_eq!(
+            port.hexdump(),
+            "00000000  68 65 6c 6c 6f 20 77 6f  72 6c 64 23 00 01 02 03  |hello world#....|\n\
+             00000010  04 23                                             |.#|\n"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_read_timeout() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 1];
+
+        port.add_delayed_response(&[0x6f, 0x6b, 0x23], Duration::from_millis(20));
+        let n = port
+            .read_timeout(read_buf.as_mut_slice(), Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(read_buf[0], 0x6f);
+
+        let mut port = m.open_native();
+        port.add_delayed_response(&[0x6f, 0x6b, 0x23], Duration::from_millis(500));
+        let err = port
+            .read_timeout(read_buf.as_mut_slice(), Duration::from_millis(20))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let mut port: MockableSerial = b"ok#".to_vec().into();
+        assert_eq!(port.address, "/dev/null");
+        assert_eq!(port.baud, 115200);
+        assert_eq!(port.stop_byte, b'#');
+        assert_eq!(std::str::from_utf8(&read_resp(&mut port)).unwrap(), "ok#");
+
+        let mut port = MockableSerial::from(&b"hi#"[..]);
+        assert_eq!(std::str::from_utf8(&read_resp(&mut port)).unwrap(), "hi#");
+    }
+
+    #[test]
+    fn test_queued_order() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.add_rule(b"ID?", b"DEV#");
+
+        port.add_response(b"first#");
+        port.write(b"ID?").unwrap();
+        port.add_response(b"last#");
+
+        assert_eq!(
+            port.queued_order(),
+            vec![b"first#".to_vec(), b"DEV#".to_vec(), b"last#".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_read_granularity() {
+        let init_resp = VecDeque::from([b"ab#".to_vec(), b"cdef#".to_vec(), b"ghi#".to_vec()]);
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, Some(init_resp));
+
+        let mut port = m.open_native();
+        port.set_read_granularity(4);
+        let mut read_buf = [0; 12];
+        let mut chunks = Vec::new();
+        while let Ok(n) = port.read(read_buf.as_mut_slice()) {
+            chunks.push(read_buf[..n].to_vec());
+        }
+        assert_eq!(
+            chunks,
+            vec![b"ab#c".to_vec(), b"def#".to_vec(), b"ghi#".to_vec()]
+        );
+
+        let mut port = m.open_native();
+        port.set_read_granularity(4);
+        assert_eq!(port.read_frame().unwrap(), b"ab#");
+        assert_eq!(port.read_frame().unwrap(), b"cdef#");
+        assert_eq!(port.read_frame().unwrap(), b"ghi#");
+    }
+
+    #[test]
+    fn test_core_smoke() {
+        // Only touches the subset of the API available without `std`
+        let mut port: MockableSerial = b"ok#".to_vec().into();
+        port.add_rule(b"ID?", b"DEV#");
+        port.write(b"ID?").unwrap();
+
+        assert_eq!(port.read_frame().unwrap(), b"ok#");
+        assert_eq!(port.read_frame().unwrap(), b"DEV#");
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_boxed() {
+        use crate::DynSerial;
+
+        let mut port: MockableSerial = b"ok#".to_vec().into();
+        port.add_rule(b"ID?", b"DEV#");
+        let mut port: Box<dyn DynSerial> = port.boxed();
+
+        assert_eq!(port.bytes_to_read().unwrap(), 3);
+        assert_eq!(port.write(b"ID?").unwrap(), 3);
+        assert_eq!(port.bytes_to_read().unwrap(), 7);
+
+        let mut read_buf = [0; 1];
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 1);
+        assert_eq!(read_buf[0], b'o');
+        assert_eq!(port.bytes_to_read().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_verify() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.expect_write(b"ID?");
+        port.expect_read(b"ok#");
+        port.expect_read(b"DEV#");
+
+        port.write(b"ID?").unwrap();
+        assert_eq!(read_resp(&mut port), b"ok#");
+
+        assert_eq!(
+            port.verify().unwrap_err(),
+            "expected read [44, 45, 56, 23] was not consumed"
+        );
+
+        read_resp(&mut port);
+        assert!(port.verify().is_ok());
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut port: MockableSerial = b"ok#".to_vec().into();
+        port.enable_recording();
+
+        port.write(b"ID?").unwrap();
+        port.read_frame().unwrap();
+
+        assert_eq!(
+            port.recording(),
+            &[
+                Interaction::Write(b"ID?".to_vec()),
+                Interaction::Read(b"ok#".to_vec())
+            ]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_timed_recording() {
+        let mut port: MockableSerial = b"ok#".to_vec().into();
+        port.enable_timed_recording();
+
+        let mut read_buf = [0; 1];
+        port.write(b"ID?").unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        port.read(read_buf.as_mut_slice()).unwrap();
+
+        let timed = port.timed_recording();
+        assert_eq!(timed.len(), 2);
+        assert_eq!(timed[1].1, Interaction::Read(b"o".to_vec()));
+
+        let gap = timed[1].0 - timed[0].0;
+        assert!(gap >= Duration::from_millis(30));
+        assert!(gap < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_reset_trigger() {
+        let init_resp = VecDeque::from([b"one#".to_vec(), b"two#".to_vec()]);
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, Some(init_resp));
+        let mut port = m.open_native();
+        port.add_reset_trigger(b"RST");
+        port.set_boot_banner(b"BOOT#");
+
+        let mut read_buf = [0; 1];
+        port.read(read_buf.as_mut_slice()).unwrap();
+        port.write(b"RST").unwrap();
+
+        assert_eq!(port.read_frame().unwrap(), b"BOOT#");
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_escaped_stop_byte() {
+        let mut port: MockableSerial = b"a\\#b\\\\#".to_vec().into();
+        port.add_response(b"next#");
+        port.set_escape(b'\\');
+
+        assert_eq!(port.read_frame().unwrap(), b"a#b\\#");
+        assert_eq!(port.read_frame().unwrap(), b"next#");
+    }
+
+    #[test]
+    fn test_baud_mismatch() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+
+        let mut port = m.open_native();
+        port.add_response(b"hello#");
+        port.set_baud_mismatch(9600);
+        let garbled = port.read_frame().unwrap();
+        assert_ne!(garbled, b"hello#");
+        assert_eq!(garbled[0], b'h' ^ 13);
+
+        let mut port = m.open_native();
+        port.add_response(b"hello#");
+        port.set_baud_mismatch(115200);
+        assert_eq!(port.read_frame().unwrap(), b"hello#");
+    }
+
+    #[test]
+    fn test_max_queue_frames() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.set_max_queue_frames(2);
+
+        port.try_add_response(b"one#").unwrap();
+        port.try_add_response(b"two#").unwrap();
+        let err = port.try_add_response(b"three#").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        port.read_frame().unwrap();
+        port.try_add_response(b"three#").unwrap();
+    }
+
+    #[test]
+    fn test_preamble() {
+        let mut port: MockableSerial = vec![0x01, 0x23, 0x02, 0xaa, 0x10, 0x20, 0x23].into();
+        port.set_preamble(0xaa);
+
+        assert_eq!(port.read_frame().unwrap(), vec![0xaa, 0x10, 0x20, 0x23]);
+        assert_eq!(port.discarded_bytes(), 3);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_read_frame_timeout() {
+        tokio::time::pause();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+
+        let mut port = m.open_native();
+        port.add_delayed_response(b"ok#", Duration::from_millis(50));
+        let frame = port.read_frame_timeout(Duration::from_millis(100)).await;
+        assert_eq!(frame.unwrap(), b"ok#");
+
+        let mut port = m.open_native();
+        port.add_delayed_response(b"ok#", Duration::from_secs(5));
+        let err = port
+            .read_frame_timeout(Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_partial_frame_on_timeout() {
+        tokio::time::pause();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+
+        port.set_return_partial_on_timeout(true);
+        port.add_response(b"ab");
+        port.add_delayed_response(b"c#", Duration::from_secs(5));
+        let err = port
+            .read_frame_timeout(Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        let partial = err
+            .into_inner()
+            .unwrap()
+            .downcast::<crate::PartialFrame>()
+            .unwrap();
+        assert_eq!(partial.0, b"ab");
+    }
+
+    #[cfg(feature = "codec")]
+    #[tokio::test]
+    async fn test_into_framed() {
+        use futures_util::StreamExt;
+
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'\n', 64, None);
+        let mut port = m.open_native();
+        port.add_response(b"hello\n");
+        port.add_response(b"world\n");
+
+        let mut framed = port.into_framed(tokio_util::codec::LinesCodec::new());
+        assert_eq!(framed.next().await.unwrap().unwrap(), "hello");
+        assert_eq!(framed.next().await.unwrap().unwrap(), "world");
+    }
+
+    #[test]
+    fn test_fail_after_reads() {
+        let mut port: MockableSerial = b"abcde#".to_vec().into();
+        port.fail_after_reads(3, ErrorKind::BrokenPipe);
+
+        let mut read_buf = [0; 1];
+        for expected in b"abc" {
+            port.read(read_buf.as_mut_slice()).unwrap();
+            assert_eq!(read_buf[0], *expected);
+        }
+
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+        port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(read_buf[0], b'd');
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_flush_timing() {
+        let m = MockableSerialBuilder::new("/dev/null", 9600, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.set_timing_simulation(true);
+
+        // 96 bytes of 10 bits each take 100ms at 9600 baud
+        port.write(&[0x55; 96]).unwrap();
+        let started = std::time::Instant::now();
+        port.flush().unwrap();
+        let elapsed = started.elapsed();
+
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_millis(500));
+
+        let started = std::time::Instant::now();
+        port.flush().unwrap();
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_from_receiver() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut port = MockableSerial::from_receiver(rx, "/dev/null", 115200, 0x23, 1);
+
+        let mut read_buf = [0; 1];
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        std::thread::spawn(move || {
+            tx.send(b"one#".to_vec()).unwrap();
+            tx.send(b"two#".to_vec()).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(read_resp(&mut port), b"one#");
+        assert_eq!(port.read_frame().unwrap(), b"two#");
+    }
+
+    #[test]
+    fn test_inject_unsolicited() {
+        let mut port: MockableSerial = b"reply#".to_vec().into();
+        port.inject_unsolicited(b"EVT#");
+
+        assert_eq!(port.read_frame().unwrap(), b"EVT#");
+        assert_eq!(port.read_frame().unwrap(), b"reply#");
+    }
+
+    #[test]
+    fn test_failed_open() {
+        let mut m: MockableSerial = b"ok#".to_vec().into();
+        m.fail_next_open();
+
+        let err = m.try_open_native().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let mut read_buf = [0; 1];
+        let err = m.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        let err = m.write(b"ID?").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+
+        m.reconnect();
+        let mut port = m.try_open_native().unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ok#");
+    }
+
+    #[test]
+    fn test_rule_trim() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        port.add_rule(b"ID?", b"DEV#");
+
+        port.write(b"ID?\r\n").unwrap();
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        port.set_rule_trim(b"\r\n");
+        port.write(b"ID?\r\n").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"DEV#");
+    }
+
+    #[test]
+    fn test_stream_1mb() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0xff, 1, None);
+        let mut port = m.open_native();
+        let mut expected = Vec::new();
+
+        for i in 0..1024 {
+            let mut frame: Vec<u8> = (0..1023).map(|j| ((i + j) % 251) as u8).collect();
+            frame.push(0xff);
+            expected.extend_from_slice(&frame);
+            port.add_response(&frame);
+        }
+
+        port.set_read_granularity(4000);
+        let mut read_buf = [0; 4000];
+        let mut received = Vec::with_capacity(expected.len());
+        while let Ok(n) = port.read(read_buf.as_mut_slice()) {
+            received.extend_from_slice(&read_buf[..n]);
+        }
+
+        assert_eq!(received.len(), 1024 * 1024);
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_generator() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut i = 0;
+
+        port.set_generator(move || {
+            i += 1;
+            (i <= 5).then(|| vec![b'0' + i, b'#'])
+        });
+
+        for i in 1..=5 {
+            assert_eq!(port.read_frame().unwrap(), vec![b'0' + i, b'#']);
+        }
+        assert_eq!(
+            port.read_frame().unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        let mut read_buf = [0; 8];
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_shared_serial_threads() {
+        use crate::{DynSerial, SharedSerial};
+
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let shared = SharedSerial::new(m.open_native());
+        let writer = shared.clone();
+        let mut reader = shared.clone();
+
+        let writer = std::thread::spawn(move || {
+            for i in 0..1000u32 {
+                writer.add_response(&[(i % 200) as u8 + 1, b'#']);
+            }
+        });
+        let reader = std::thread::spawn(move || {
+            let mut read_buf = [0; 8];
+            let mut received = Vec::new();
+            while received.len() < 2000 {
+                match reader.read(read_buf.as_mut_slice()) {
+                    Ok(n) => received.extend_from_slice(&read_buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::yield_now(),
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            received
+        });
+
+        writer.join().unwrap();
+        let received = reader.join().unwrap();
+        let expected: Vec<u8> = (0..1000u32)
+            .flat_map(|i| [(i % 200) as u8 + 1, b'#'])
+            .collect();
+        assert_eq!(received, expected);
+        assert_eq!(shared.bytes_to_read().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_line_errors() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.add_response(b"OK#");
+
+        port.inject_parity_error();
+        port.inject_break();
+        assert!(matches!(
+            port.read_detailed(read_buf.as_mut_slice()),
+            Err(SerialError::Parity)
+        ));
+        assert!(matches!(
+            port.read_detailed(read_buf.as_mut_slice()),
+            Err(SerialError::Break)
+        ));
+        assert_eq!(port.read_detailed(read_buf.as_mut_slice()).unwrap(), 3);
+        assert!(matches!(
+            port.read_detailed(read_buf.as_mut_slice()),
+            Err(SerialError::Io(e)) if e.kind() == ErrorKind::WouldBlock
+        ));
+
+        // Plain reads see the same conditions as InvalidData
+        port.inject_framing_error();
+        let e = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "Framing error");
+    }
+
+    #[test]
+    fn test_read_byte() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_response(b"AB#");
+
+        let mut read_buf = [0; 4];
+        port.read_byte(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(read_buf, [b'A', 0, 0, 0]);
+        assert_eq!(read_resp(&mut port), b"B#");
+    }
+
+    #[test]
+    fn test_read_n_frames() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        for r in [b"A#", b"B#", b"C#", b"D#"] {
+            port.add_response(r);
+        }
+
+        assert_eq!(
+            port.read_n_frames(2).unwrap(),
+            vec![b"A#".to_vec(), b"B#".to_vec()]
+        );
+        assert_eq!(port.queued_order(), vec![b"C#".to_vec(), b"D#".to_vec()]);
+
+        let err = port.read_n_frames(3).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(port.queued_order().len(), 2);
+    }
+
+    #[test]
+    fn test_drop_bad_crc() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_drop_bad_crc();
+
+        let mut good = b"OK".to_vec();
+        good.extend_from_slice(&crc16(b"OK").to_le_bytes());
+        assert_eq!(crc16(b"123456789"), 0x4b37);
+        assert!(verify_crc16(&good));
+        good.push(b'#');
+
+        let mut bad = good.clone();
+        bad[0] = b'N';
+        assert!(!verify_crc16(&bad[..4]));
+
+        port.add_response(&bad);
+        port.add_response(&good);
+        port.add_response(&bad);
+
+        assert_eq!(port.read_frame().unwrap(), good);
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_set_address() {
+        let m = MockableSerialBuilder::new("/dev/ttyUSB0", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        assert_eq!(port.name().as_deref(), Some("/dev/ttyUSB0"));
+
+        port.set_closed();
+        port.set_address("/dev/ttyUSB1");
+        port.reconnect();
+        assert_eq!(port.name().as_deref(), Some("/dev/ttyUSB1"));
+    }
+
+    #[test]
+    fn test_rx_overrun() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 16, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 16];
+        port.set_rx_capacity(6);
+
+        port.add_response(b"AB#");
+        port.add_response(b"CDEF#");
+        port.add_response(b"GH#");
+
+        assert!(matches!(
+            port.read_detailed(read_buf.as_mut_slice()),
+            Err(SerialError::Overrun)
+        ));
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+        assert_eq!(&read_buf[..3], b"AB#");
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+        assert_eq!(&read_buf[..3], b"CDE");
+        assert_eq!(
+            port.read(read_buf.as_mut_slice()).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_jitter() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut a = m.open_native();
+        let mut b = m.open_native();
+        a.set_jitter(Duration::from_millis(10), Duration::from_millis(5), 42);
+        b.set_jitter(Duration::from_millis(10), Duration::from_millis(5), 42);
+
+        let (ja, jb) = (a.jitter.as_mut().unwrap(), b.jitter.as_mut().unwrap());
+        for _ in 0..100 {
+            let d = ja.next_delay();
+            assert_eq!(d, jb.next_delay());
+            assert!(d >= Duration::from_millis(5) && d <= Duration::from_millis(15));
+        }
+
+        let mut read_buf = [0; 8];
+        a.add_response(b"OK#");
+        assert_eq!(
+            a.read(read_buf.as_mut_slice()).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        let n = a
+            .read_timeout(read_buf.as_mut_slice(), Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(&read_buf[..n], b"OK#");
+    }
+
+    #[test]
+    #[cfg(feature = "bytes")]
+    fn test_bytes() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_response_bytes(bytes::Bytes::from_static(b"OK#"));
+        assert_eq!(
+            port.read_bytes().unwrap(),
+            bytes::Bytes::from_static(b"OK#")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_from_capture_log() {
+        let path = std::env::temp_dir().join(format!("serial_mock_{}.cap", std::process::id()));
+        std::fs::write(&path, b"\x00\xffA\r\nB\x1b\n\n\x7f").unwrap();
+
+        let mut port = MockableSerial::from_capture_log(&path, b'\n').unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(port.queued_order().len(), 4);
+        assert_eq!(port.read_frame().unwrap(), b"\x00\xffA\r\n");
+        assert_eq!(port.read_frame().unwrap(), b"B\x1b\n");
+        assert_eq!(port.read_frame().unwrap(), b"\n");
+    }
+
+    #[test]
+    fn test_newline_normalize() {
+        for (stop_byte, response) in [(b'\r', &b"OK\r"[..]), (b'\n', b"OK\n"), (b'\n', b"OK\r\n")] {
+            let m = MockableSerialBuilder::new("/dev/null", 115200, stop_byte, 8, None);
+            let mut port = m.open_native();
+            port.set_read_newline_normalize(b"\n".to_vec());
+            port.add_response(response);
+
+            assert_eq!(port.read_frame().unwrap(), b"OK\n");
+        }
+    }
+
+    #[test]
+    fn test_rules_listing() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_rule(b"ID?", b"MOCK#");
+        port.add_rule(b"V?", b"1.0#");
+        port.add_prefix_rule(b"SET", b"OK#");
+
+        assert_eq!(
+            port.rules(),
+            vec![
+                (b"ID?".to_vec(), b"MOCK#".to_vec()),
+                (b"V?".to_vec(), b"1.0#".to_vec())
+            ]
+        );
+        assert_eq!(
+            port.prefix_rules(),
+            vec![(b"SET".to_vec(), b"OK#".to_vec())]
+        );
+
+        port.write(b"SET 5").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"OK#");
+    }
+
+    #[test]
+    fn test_handshake() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_rule(b"ID?", b"MOCK#");
+        port.require_handshake(b"HELLO", b"READY#");
+        port.add_response(b"BOOT#");
+
+        port.write(b"ID?").unwrap();
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        port.write(b"HELLO").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"READY#");
+        assert_eq!(port.read_frame().unwrap(), b"BOOT#");
+        port.write(b"ID?").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"MOCK#");
+        assert!(port.read_frame().is_err());
+    }
+
+    #[test]
+    fn test_busy() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.add_response(b"A#");
+        port.add_response(b"B#");
+
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 2);
+
+        port.simulate_busy();
+        let e = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ResourceBusy);
+        let e = port.write(b"ID?").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ResourceBusy);
+
+        port.release();
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 2);
+        assert_eq!(&read_buf[..2], b"B#");
+    }
+
+    #[test]
+    fn test_expect_write_matching() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.expect_write_matching(Contains(b"TEMP".to_vec()));
+        port.expect_write_matching(StartsWith(b"GET".to_vec()));
+
+        port.write(b"SET TEMP=21").unwrap();
+        assert_eq!(
+            port.verify().unwrap_err(),
+            "no write matched StartsWith([47, 45, 54])"
+        );
+
+        port.write(b"GET 1").unwrap();
+        assert!(port.verify().is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_burst() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        let gap = Duration::from_millis(10);
+
+        let start = std::time::Instant::now();
+        port.add_burst(vec![b"A#".to_vec(), b"B#".to_vec(), b"C#".to_vec()], gap);
+
+        for (i, expected) in [b"A#", b"B#", b"C#"].iter().enumerate() {
+            let n = port
+                .read_timeout(read_buf.as_mut_slice(), Duration::from_millis(500))
+                .unwrap();
+            assert_eq!(&read_buf[..n], &expected[..]);
+            assert!(start.elapsed() >= gap * i as u32);
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_lifo_order() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_order(QueueOrder::Lifo);
+        for r in [b"A#", b"B#", b"C#"] {
+            port.add_response(r);
+        }
+
+        assert_eq!(port.queued_order()[0], b"C#");
+        assert_eq!(read_resp(&mut port), b"C#");
+        assert_eq!(port.read_frame().unwrap(), b"B#");
+        assert_eq!(port.read_frame().unwrap(), b"A#");
+    }
+
+    #[test]
+    fn test_remaining_bytes() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 1, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 1];
+        port.add_response(b"ok#");
+        port.add_response(b"DEV#");
+        assert_eq!(port.remaining_bytes(), b"ok#DEV#");
+
+        port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(port.remaining_bytes(), b"k#DEV#");
+    }
+
+    #[test]
+    fn test_parity_error_at() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.add_response(b"ABCDE#");
+        port.inject_parity_error_at(3);
+
+        assert_eq!(port.read_detailed(read_buf.as_mut_slice()).unwrap(), 3);
+        assert_eq!(&read_buf[..3], b"ABC");
+        assert!(matches!(
+            port.read_detailed(read_buf.as_mut_slice()),
+            Err(SerialError::Parity)
+        ));
+        assert_eq!(port.read_detailed(read_buf.as_mut_slice()).unwrap(), 3);
+        assert_eq!(&read_buf[..3], b"DE#");
+    }
+
+    #[test]
+    fn test_validate_frames() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_response(b"ok#");
+        assert!(port.validate_frames().is_ok());
+
+        port.add_response(b"a#b#");
+        assert_eq!(
+            port.validate_frames().unwrap_err(),
+            "response [61, 23, 62, 23] has a stop byte at 1"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_read_cancellable() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let flag = cancel.clone();
+        let reader = std::thread::spawn(move || {
+            let mut read_buf = [0; 8];
+            port.read_cancellable(read_buf.as_mut_slice(), &flag)
+        });
+
+        std::thread::sleep(Duration::from_millis(20));
+        cancel.store(true, Ordering::Release);
+        let err = reader.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_latency_profile() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let profile = [5, 20, 5].map(Duration::from_millis);
+        port.add_rule(b"ID?", b"MOCK#");
+        port.set_latency_profile(profile.to_vec());
+
+        for sample in profile {
+            let before = std::time::Instant::now();
+            port.write(b"ID?").unwrap();
+            let after = std::time::Instant::now();
+
+            let (due, _) = port.delayed_queue.back().unwrap();
+            assert!(*due >= before + sample && *due <= after + sample);
+            port.delayed_queue.clear();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_tee() {
+        use std::sync::{Arc, Mutex};
+
+        struct Sink(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_tee(Sink(captured.clone()));
+        port.add_rule(b"ID?", b"ok#");
+
+        port.write(b"ID?").unwrap();
+        port.read_frame().unwrap();
+
+        assert_eq!(
+            String::from_utf8(captured.lock().unwrap().clone()).unwrap(),
+            "TX 49 44 3f\nRX 6f 6b 23\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_startup_delay() {
+        let mut m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        m.set_startup_delay(Duration::from_millis(30));
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.add_response(b"ok#");
+
+        let e = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        let e = port.write(b"ID?").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+
+        let n = port
+            .read_timeout(read_buf.as_mut_slice(), Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(&read_buf[..n], b"ok#");
+        assert!(port.write(b"ID?").is_ok());
+    }
+
+    #[test]
+    fn test_bytes_iter() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_response(b"ok#");
+        port.add_response(b"DEV#");
+
+        let all: Result<Vec<u8>, _> = port.bytes().collect();
+        assert_eq!(all.unwrap(), b"ok#DEV#");
+        assert_eq!(port.bytes().count(), 0);
+    }
+
+    #[test]
+    fn test_rule_strategy() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_prefix_rule(b"S", b"short#");
+        port.add_prefix_rule(b"SET", b"long#");
+        port.add_prefix_rule(b"SE", b"mid#");
+
+        for (strategy, expected) in [
+            (RuleStrategy::FirstMatch, b"short#".as_slice()),
+            (RuleStrategy::LastMatch, b"mid#"),
+            (RuleStrategy::MostSpecific, b"long#"),
+        ] {
+            port.set_rule_strategy(strategy);
+            port.write(b"SET 1").unwrap();
+            assert_eq!(port.read_frame().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_truncated_response() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_response(b"ok#");
+        port.add_truncated_response(b"TEMP=2");
+
+        assert_eq!(port.read_frame().unwrap(), b"ok#");
+        assert_eq!(port.read_frame().unwrap(), b"TEMP=2");
+        assert_eq!(
+            port.read_frame().unwrap_err().kind(),
+            ErrorKind::NotConnected
+        );
+    }
+
+    #[test]
+    fn test_fluent_assertions() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_rule(b"ID?", b"ok#");
+
+        port.write(b"ID?").unwrap();
+        port.read_frame().unwrap();
+        port.assert_wrote(b"ID?").assert_read_count(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a write of [56, 3f], got [[49, 44, 3f]]")]
+    fn test_assert_wrote_message() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.write(b"ID?").unwrap();
+        port.assert_wrote(b"V?");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_rule_with_delay() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_rule_with_delay(b"SLOW", b"slow#", Duration::from_millis(50));
+        port.add_rule(b"FAST", b"fast#");
+
+        port.write(b"SLOW").unwrap();
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+        port.write(b"FAST").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"fast#");
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(port.read_frame().unwrap(), b"slow#");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_rule_delay_stays_with_its_rule() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_rule_with_delay(b"A", b"slow#", Duration::from_secs(60));
+        port.add_rule(b"A", b"fast#");
+
+        port.set_rule_strategy(crate::RuleStrategy::LastMatch);
+        port.write(b"A").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"fast#");
+
+        port.set_rule_strategy(crate::RuleStrategy::FirstMatch);
+        port.add_prefix_rule(b"A", b"prefix#");
+        port.write(b"AB").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"prefix#");
+        port.write(b"A").unwrap();
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_frame_transmission_time() {
+        let m = MockableSerialBuilder::new("/dev/null", 9600, b'#', 8, None);
+        let mut port = m.open_native();
+
+        // 8N1 is 10 bits per byte, 96 bytes take 960 bits, i.e. 100ms
+        assert_eq!(port.frame_transmission_time(96), Duration::from_millis(100));
+
+        port.set_parity(crate::Parity::Even);
+        port.set_stop_bits(2);
+        assert_eq!(port.frame_transmission_time(96), Duration::from_millis(120));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_read_frame_or_timeout() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_delayed_response(b"ok#", Duration::from_millis(10));
+        let frame = port.read_frame_or_timeout(Duration::from_millis(500));
+        assert_eq!(frame.unwrap(), Some(b"ok#".to_vec()));
+
+        let frame = port.read_frame_or_timeout(Duration::from_millis(10));
+        assert_eq!(frame.unwrap(), None);
+
+        port.fail_after_reads(0, ErrorKind::BrokenPipe);
+        let err = port
+            .read_frame_or_timeout(Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_state_machine() {
+        use alloc::string::String;
+
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_state_machine(Box::new(|cmd, state| match cmd {
+            b"SET" => {
+                state.insert(String::from("mode"), String::from("armed"));
+                Some(b"OK#".to_vec())
+            }
+            b"GET" => match state.get("mode").map(|m| m.as_str()) {
+                Some("armed") => Some(b"ARMED#".to_vec()),
+                _ => Some(b"IDLE#".to_vec()),
+            },
+            _ => None,
+        }));
+
+        port.write(b"GET").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"IDLE#");
+        port.write(b"SET").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"OK#");
+        port.write(b"GET").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ARMED#");
+        assert_eq!(port.state().get("mode").unwrap(), "armed");
+    }
+
+    #[test]
+    fn test_validate_scenario() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_response(b"ok");
+        port.add_rule(b"ID?", b"a#b#");
+        port.add_rule(b"ID?", b"MOCK#");
+        port.add_prefix_rule(b"S", b"ok#");
+        port.add_prefix_rule(b"SET", b"set#");
+
+        assert_eq!(
+            port.validate_scenario(),
+            vec![
+                "response [6f, 6b] does not end with the stop byte",
+                "rule [49, 44, 3f] response has a stop byte at 1",
+                "duplicate rule [49, 44, 3f]",
+                "prefix rule [53, 45, 54] is shadowed by prefix rule [53]",
+            ]
+        );
+
+        port.set_rule_strategy(RuleStrategy::LastMatch);
+        port.add_rule(b"SET", b"exact#");
+        assert!(port
+            .validate_scenario()
+            .contains(&"rule [53, 45, 54] is shadowed by prefix rule [53, 45, 54]".to_string()));
+    }
+
+    #[test]
+    fn test_cobs() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_framing(Framing::Cobs);
+
+        let payload = [0x11, 0x00, 0x00, 0x22, 0x33, 0x00];
+        assert_eq!(
+            cobs_encode(&payload),
+            [0x02, 0x11, 0x01, 0x03, 0x22, 0x33, 0x01]
+        );
+        let long: Vec<u8> = (1..=254).collect();
+        assert_eq!(cobs_decode(&cobs_encode(&long)).unwrap(), long);
+
+        port.add_cobs_response(&payload);
+        port.add_cobs_response(&[]);
+        assert_eq!(port.read_frame().unwrap(), payload);
+        assert_eq!(port.read_frame().unwrap(), b"");
+    }
+
+    #[test]
+    fn test_byte_reverse() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_response(b"abc#");
+        port.add_response(b"abc#");
+
+        port.set_byte_reverse(true);
+        assert_eq!(port.read_frame().unwrap(), b"cba#");
+        port.set_byte_reverse(false);
+        assert_eq!(port.read_frame().unwrap(), b"abc#");
+    }
+
+    #[test]
+    fn test_duplicate_writes() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.write(b"ID?").unwrap();
+        port.write(b"ID?").unwrap();
+        port.write(b"V?").unwrap();
+        assert_eq!(port.duplicate_writes(), 1);
+
+        port.forbid_duplicate_writes();
+        let e = port.write(b"V?").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert_eq!(port.written_messages().len(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "notify")]
+    fn test_watch_responses_file() {
+        let path = std::env::temp_dir().join(format!("serial_mock_{}.hex", std::process::id()));
+        std::fs::write(&path, "6f 6b 23\n").unwrap();
+
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.watch_responses_file(&path).unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ok#");
+
+        std::fs::write(&path, "6f 6b 23\n\n444556 23\n").unwrap();
+        port.reload_responses_file().unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"DEV#");
+        assert!(port.read_frame().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_manual_clock() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.set_clock(clock.clone());
+
+        port.add_delayed_response(b"ok#", Duration::from_secs(60));
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+        clock.advance(Duration::from_secs(59));
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+
+        // Waiting on a manual clock takes no real time
+        let started = std::time::Instant::now();
+        port.add_delayed_response(b"ok#", Duration::from_secs(3600));
+        let n = port
+            .read_timeout(read_buf.as_mut_slice(), Duration::from_secs(7200))
+            .unwrap();
+        assert_eq!(n, 3);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_byte_duplication() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 16, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 16];
+
+        port.set_byte_duplication(1.0, 7);
+        port.add_response(b"ab#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"aabb##");
+
+        // A doubled byte that does not fit is held for the next read
+        port.add_response(b"cd#");
+        let n = port.read(&mut read_buf[..3]).unwrap();
+        assert_eq!(&read_buf[..n], b"ccd");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"d##");
+
+        port.set_byte_duplication(0.0, 7);
+        port.add_response(b"ab#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"ab#");
+    }
+
+    #[test]
+    fn test_read_into_ring() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut ring = VecDeque::with_capacity(8);
+        ring.push_back(b'x');
+
+        port.add_response(b"ab#");
+        port.add_response(b"cd#");
+        assert_eq!(port.read_into_ring(&mut ring, 4).unwrap(), 4);
+        assert_eq!(ring, b"xab#c");
+        assert_eq!(port.read_into_ring(&mut ring, 8).unwrap(), 2);
+        assert_eq!(ring, b"xab#cd#");
+        assert_eq!(
+            port.read_into_ring(&mut ring, 8).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_rule_case_insensitive() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_rule(b"id?", b"mock#");
+        port.add_prefix_rule(b"set", b"ok#");
+        port.write(b"ID?").unwrap();
+        assert!(!port.has_complete_frame());
+
+        port.set_rule_case_insensitive(true);
+        port.write(b"ID?").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"mock#");
+        port.write(b"SET 1").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ok#");
+    }
+
+    #[test]
+    fn test_internal_error() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 1];
+
+        port.add_response(b"a#");
+        assert_eq!(port.take_internal_error(), None);
+        assert_eq!(
+            port.read_byte(&mut []).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            port.take_internal_error().unwrap(),
+            "read_byte called with an empty buffer"
+        );
+        assert_eq!(port.take_internal_error(), None);
+
+        // The port keeps working afterwards
+        port.read_byte(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(read_buf[0], b'a');
+    }
+
+    #[test]
+    fn test_disconnect_behavior() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.add_response(b"ok#");
+        port.set_closed();
+        assert_eq!(
+            port.read(read_buf.as_mut_slice()).unwrap_err().kind(),
+            ErrorKind::NotConnected
+        );
+
+        port.set_disconnect_behavior(DisconnectBehavior::Error(ErrorKind::BrokenPipe));
+        assert_eq!(
+            port.read(read_buf.as_mut_slice()).unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+
+        port.set_disconnect_behavior(DisconnectBehavior::Eof);
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 0);
+        assert_eq!(
+            port.read_frame().unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            port.write(b"x").unwrap_err().kind(),
+            ErrorKind::NotConnected
+        );
+
+        port.reconnect();
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_response_with_terminator() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.add_response(b"a\nb#");
+        port.add_response_with_terminator(b"c#d", b'\n');
+        port.add_response_with_terminator(b"e", b'#');
+        assert!(port.validate_frames().is_ok());
+
+        assert_eq!(
+            port.read_n_frames(3).unwrap(),
+            vec![b"a\nb#".to_vec(), b"c#d\n".to_vec(), b"e#".to_vec()]
+        );
+
+        port.add_response_with_terminator(b"fg", b'\n');
+        port.add_response(b"h#");
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+        assert_eq!(&read_buf[..3], b"fg\n");
+    }
+
+    #[test]
+    fn test_poll_only() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.set_poll_only(true);
+        port.add_rule(b"T?", b"21#");
+        port.add_rule(b"H?", b"40#");
+        port.write(b"T?").unwrap();
+        port.write(b"H?").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"40#");
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        port.set_poll_only(false);
+        port.write(b"T?").unwrap();
+        port.write(b"H?").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"21#");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_uptime() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_clock(clock.clone());
+
+        assert_eq!(port.uptime(), Duration::ZERO);
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(port.uptime(), Duration::from_millis(250));
+
+        // A reopened port starts counting again
+        assert_eq!(port.open_native().uptime(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_slip_framing() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        let payload = [0x01, 0xc0, 0x02, 0xdb, 0x03];
+        let encoded = slip_encode(&payload);
+        assert_eq!(encoded, [0x01, 0xdb, 0xdc, 0x02, 0xdb, 0xdd, 0x03]);
+        assert_eq!(slip_decode(&encoded).unwrap(), payload);
+        assert_eq!(slip_decode(&[0xdb, 0x01]), None);
+
+        port.set_framing(Framing::Slip);
+        port.add_slip_response(&payload);
+        port.add_slip_response(b"");
+        assert_eq!(port.read_frame().unwrap(), payload);
+        assert_eq!(port.read_frame().unwrap(), b"");
+
+        port.add_response(&[0xdb, 0x01, 0xc0]);
+        assert_eq!(
+            port.read_frame().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_response_after_read() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 2, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.add_response(b"boot#");
+        port.add_response_after_read(1, b"ready#");
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 2);
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 2);
+        assert_eq!(port.queued_order(), vec![b"#".to_vec()]);
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 1);
+        assert_eq!(port.read_frame().unwrap(), b"ready#");
+
+        port.add_response_after_read(1, b"late#");
+        assert_eq!(port.read_frame().unwrap(), b"late#");
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = MockRegistry::new();
+        let mut gps =
+            MockableSerialBuilder::new("/dev/ttyUSB0", 9600, b'\n', 32, None).open_native();
+        gps.add_response(b"$GPGGA\n");
+        let mut modem =
+            MockableSerialBuilder::new("/dev/ttyUSB1", 115200, b'\r', 32, None).open_native();
+        modem.add_rule(b"AT", b"OK\r");
+        registry.register("gps", gps);
+        registry.register("modem", modem);
+
+        let mut gps = registry.open("gps").unwrap();
+        assert_eq!(gps.read_frame().unwrap(), b"$GPGGA\n");
+        let mut modem = registry.open("modem").unwrap();
+        modem.write(b"AT").unwrap();
+        assert_eq!(modem.read_frame().unwrap(), b"OK\r");
+
+        registry.get_mut("gps").unwrap().fail_next_open();
+        assert_eq!(
+            registry.open("gps").err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            registry.get("modem").unwrap().name().unwrap(),
+            "/dev/ttyUSB1"
+        );
+        assert!(registry.open("scale").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_write_timestamps() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_clock(clock.clone());
+
+        clock.advance(Duration::from_millis(5));
+        port.write(b"START").unwrap();
+        clock.advance(Duration::from_millis(20));
+        port.write(b"STOP").unwrap();
+
+        let times = port.write_timestamps();
+        assert_eq!(
+            times,
+            vec![Duration::from_millis(5), Duration::from_millis(25)]
+        );
+        assert!(times[1] - times[0] <= Duration::from_millis(20));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_bootloop() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_clock(clock.clone());
+
+        port.set_bootloop(b"BOOT#", Duration::from_secs(2));
+        port.add_rule(b"ID?", b"mock#");
+        for _ in 0..3 {
+            assert_eq!(port.read_frame().unwrap(), b"BOOT#");
+            port.write(b"ID?").unwrap();
+            clock.advance(Duration::from_secs(2));
+        }
+
+        // Booting again drops the reply that was never read
+        assert_eq!(port.read_frame().unwrap(), b"BOOT#");
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_skip_to() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_response(b"\xff\x00#");
+        port.add_response(b"zz$ok#");
+        assert_eq!(port.skip_to(b'$').unwrap(), 5);
+        assert_eq!(port.skip_to(b'$').unwrap(), 0);
+        assert_eq!(port.read_frame().unwrap(), b"$ok#");
+        assert_eq!(port.discarded_bytes(), 5);
+
+        port.add_response(b"ab#");
+        assert_eq!(
+            port.skip_to(b'$').unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(port.discarded_bytes(), 8);
+    }
+
+    #[test]
+    fn test_supported_bauds() {
+        let m = MockableSerialBuilder::new("/dev/null", 9600, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.set_baud_rate(100000);
+        assert_eq!(port.effective_baud(), 100000);
+
+        port.set_supported_bauds(&[115200, 9600]);
+        port.set_baud_rate(100000);
+        assert_eq!(port.requested_baud(), 100000);
+        assert_eq!(port.effective_baud(), 115200);
+        port.set_baud_rate(14400);
+        assert_eq!(port.effective_baud(), 9600);
+    }
+
+    #[test]
+    fn test_frame_error_at() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.add_response(b"abcdef#");
+        port.inject_frame_error_at(2);
+        port.inject_parity_error_at(4);
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 2);
+        assert!(matches!(
+            port.read_detailed(read_buf.as_mut_slice()),
+            Err(SerialError::Framing)
+        ));
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 2);
+        assert_eq!(&read_buf[..2], b"cd");
+        assert!(matches!(
+            port.read_detailed(read_buf.as_mut_slice()),
+            Err(SerialError::Parity)
+        ));
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_empty_read_kind() {
+        let mut port = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None)
+            .empty_read_kind(ErrorKind::TimedOut);
+        let mut read_buf = [0; 8];
+
+        assert_eq!(
+            port.read(read_buf.as_mut_slice()).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+        port.add_response(b"ok#");
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+
+        // Reopening keeps the setting
+        let mut port = port.open_native();
+        assert_eq!(
+            port.read(read_buf.as_mut_slice()).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn test_software_flow_control() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_software_flow_control(true);
+        port.add_rule(b"ID?", b"mock#");
+        port.write(&[XOFF]).unwrap();
+        port.write(b"ID?").unwrap();
+        assert_eq!(
+            port.read(read_buf.as_mut_slice()).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        port.write(&[XON]).unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"mock#");
+
+        // Flow bytes inside a command don't break rule matching
+        port.write(&[b'I', XOFF, b'D', b'?']).unwrap();
+        assert!(port.read_frame().is_err());
+        port.write(&[XON]).unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"mock#");
+    }
+
+    #[test]
+    fn test_consumed_in_frame() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 3];
+
+        port.add_response(b"hello#bye#");
+        assert_eq!(port.consumed_in_frame(), b"");
+        port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(port.consumed_in_frame(), b"hel");
+        port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(port.consumed_in_frame(), b"");
+        port.read(&mut read_buf[..1]).unwrap();
+        assert_eq!(port.consumed_in_frame(), b"b");
+    }
+
+    #[test]
+    fn test_auto_ack() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_auto_ack(0x06);
+        port.add_rule(b"ID?", b"mock#");
+        port.write(b"SET 1").unwrap();
+        port.write(b"SET 2").unwrap();
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 2);
+        assert_eq!(&read_buf[..2], [0x06, 0x06]);
+
+        port.write(b"ID?").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"mock#");
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+    }
+
+    #[test]
+    fn test_recordings_equal() {
+        let baseline = vec![
+            Interaction::Write(b"ID?".to_vec()),
+            Interaction::Read(b"mock#".to_vec()),
+        ];
+        assert!(recordings_equal(&baseline, &baseline.clone()).is_ok());
+
+        let run = vec![
+            Interaction::Write(b"ID?".to_vec()),
+            Interaction::Read(b"mick#".to_vec()),
+        ];
+        assert_eq!(
+            recordings_equal(&baseline, &run).unwrap_err(),
+            "interaction 1 (read) differs at byte 1: [6d, 6f, 63, 6b, 23] vs [6d, 69, 63, 6b, 23]"
+        );
+
+        let run = vec![Interaction::Read(b"ID?".to_vec())];
+        assert!(recordings_equal(&baseline, &run)
+            .unwrap_err()
+            .starts_with("interaction 0: Write("));
+        assert_eq!(
+            recordings_equal(&baseline, &baseline[..1]).unwrap_err(),
+            "transcripts have 2 and 1 interactions"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_read_at_least() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 16, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 16];
+        port.set_clock(clock.clone());
+
+        port.add_delayed_response(b"ab#", Duration::from_millis(10));
+        port.add_delayed_response(b"cd#", Duration::from_millis(30));
+        let n = port
+            .read_at_least(5, read_buf.as_mut_slice(), Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(&read_buf[..n], b"ab#cd#");
+        assert_eq!(port.uptime(), Duration::from_millis(30));
+
+        port.add_delayed_response(b"ef#", Duration::from_millis(10));
+        assert_eq!(
+            port.read_at_least(4, read_buf.as_mut_slice(), Duration::from_millis(100))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn test_usb_info() {
+        let m = MockableSerialBuilder::new("/dev/ttyUSB0", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        assert_eq!(port.usb_info(), None);
+        port.set_usb_info(0x0403, 0x6001, "A50285BI");
+        let info = UsbPortInfo {
+            vid: 0x0403,
+            pid: 0x6001,
+            serial_number: Some("A50285BI".to_string()),
+        };
+        assert_eq!(port.usb_info(), Some(info.clone()));
+        assert_eq!(port.open_native().usb_info(), Some(info));
+
+        port.set_usb_info(0x1a86, 0x7523, "");
+        assert_eq!(port.usb_info().unwrap().serial_number, None);
+    }
+
+    #[test]
+    fn test_flaky_connection() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_flaky_connection(3);
+        port.add_rule(b"ID?", b"mock#");
+        port.write(b"ID?").unwrap();
+        port.write(b"ID?").unwrap();
+        assert_eq!(
+            port.read(read_buf.as_mut_slice()).unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 5);
+        port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(
+            port.write(b"ID?").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+        assert_eq!(port.written_messages().len(), 2);
+    }
+
+    #[test]
+    fn test_switch_to_raw() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 16, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 16];
+
+        port.add_response(b"MODE RAW#\x01\x02#\x03");
+        port.add_response(b"\x04#\x05");
+        assert_eq!(port.read_frame().unwrap(), b"MODE RAW#");
+
+        port.set_unframed();
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"\x01\x02#\x03\x04#\x05");
+
+        port.add_response(b"\x06#\x07");
+        assert_eq!(port.read_frame().unwrap(), b"\x06#\x07");
+
+        port.set_framing(Framing::StopByte);
+        port.add_response(b"ok#more#");
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_assert_remaining_eq() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 2, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 2];
+
+        port.add_response(b"ab#");
+        port.add_response(b"c#");
+        port.read(read_buf.as_mut_slice()).unwrap();
+        port.assert_remaining_eq(b"#c#");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "remaining bytes differ at offset 1: expected [61, 63, 23], got [61, 62, 23]"
+    )]
+    fn test_assert_remaining_eq_message() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_response(b"ab#");
+        port.assert_remaining_eq(b"ac#");
+    }
+
+    #[test]
+    fn test_rule_for_version() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_rule_for_version("2.0", b"TEMP?", b"21.5#");
+        port.write(b"TEMP?").unwrap();
+        assert!(!port.has_complete_frame());
+
+        port.set_firmware_version("1.0");
+        port.write(b"TEMP?").unwrap();
+        assert!(!port.has_complete_frame());
+
+        port.set_firmware_version("v2.0.1");
+        port.write(b"TEMP?").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"21.5#");
+
+        port.set_firmware_version("1.10");
+        port.add_rule_for_version("1.9", b"ID?", b"mock#");
+        port.write(b"ID?").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"mock#");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_length_proportional_latency() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut short = m.open_native();
+        let mut long = m.open_native();
+        short.set_clock(clock.clone());
+        long.set_clock(clock.clone());
+
+        short.set_length_proportional_latency(Duration::from_millis(1));
+        long.set_length_proportional_latency(Duration::from_millis(1));
+        short.add_response(b"1234#");
+        long.add_response(b"123456789#");
+
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(short.read_frame().unwrap(), b"1234#");
+        assert!(long.read_frame().is_err());
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(long.read_frame().unwrap(), b"123456789#");
+    }
+
+    #[test]
+    #[cfg(feature = "serialport")]
+    fn test_passthrough() {
+        use crate::SharedSerial;
+
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let real = SharedSerial::new(m.open_native());
+        let mut port = m.open_native();
+
+        real.lock().add_rule(b"ID?", b"real#");
+        port.set_passthrough(Box::new(real.clone()));
+        port.add_response(b"mock#");
+
+        port.write(b"ID?").unwrap();
+        assert_eq!(real.lock().written_messages(), [b"ID?".to_vec()]);
+        assert_eq!(port.read_frame().unwrap(), b"mock#");
+        assert_eq!(port.read_frame().unwrap(), b"real#");
+        assert!(port.read_frame().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serialport")]
+    fn test_available_mock_ports() {
+        let listed = |name: &str| {
+            crate::available_mock_ports()
+                .iter()
+                .any(|p| p.port_name == name)
+        };
+        let mut registry = MockRegistry::new();
+        let mut usb = MockableSerialBuilder::new("/dev/ttyMOCK0", 115200, b'#', 8, None);
+        usb.set_usb_info(0x0403, 0x6001, "A50285BI");
+        registry.register("usb", usb);
+        registry.register(
+            "uart",
+            MockableSerialBuilder::new("/dev/ttyMOCK1", 115200, b'#', 8, None),
+        );
+
+        let ports = crate::available_mock_ports();
+        let usb = ports
+            .iter()
+            .find(|p| p.port_name == "/dev/ttyMOCK0")
+            .unwrap();
+        assert!(matches!(
+            &usb.port_type,
+            serialport::SerialPortType::UsbPort(info) if info.vid == 0x0403
+        ));
+        assert!(listed("/dev/ttyMOCK1"));
+
+        registry.remove("uart");
+        assert!(!listed("/dev/ttyMOCK1"));
+        drop(registry);
+        assert!(!listed("/dev/ttyMOCK0"));
+    }
+
+    #[test]
+    fn test_cycling_rule() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_rule(b"MODE?", b"never#");
+        port.add_cycling_rule(
+            b"MODE?",
+            vec![b"A#".to_vec(), b"B#".to_vec(), b"C#".to_vec()],
+        );
+        for expected in [b"A#", b"B#", b"C#", b"A#"] {
+            port.write(b"MODE?").unwrap();
+            assert_eq!(port.read_frame().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_wait_until_drained() {
+        use crate::{DynSerial, SharedSerial};
+
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let shared = SharedSerial::new(m.open_native());
+        shared.add_response(b"one#");
+        shared.add_response(b"two#");
+        assert_eq!(
+            shared
+                .wait_until_drained(Duration::from_millis(5))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+
+        let mut reader = shared.clone();
+        let handle = std::thread::spawn(move || {
+            let mut read_buf = [0; 8];
+            for _ in 0..2 {
+                std::thread::sleep(Duration::from_millis(5));
+                reader.read(read_buf.as_mut_slice()).unwrap();
+            }
+        });
+        shared.wait_until_drained(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_fill_random_frames() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut other = m.open_native();
+
+        port.fill_random_frames(200, 1..32, 9);
+        other.fill_random_frames(200, 1..32, 9);
+        assert_eq!(port.remaining_bytes(), other.remaining_bytes());
+
+        let frames = port.read_n_frames(200).unwrap();
+        for frame in &frames {
+            assert!((2..=32).contains(&frame.len()));
+            assert_eq!(frame.iter().position(|b| *b == b'#'), Some(frame.len() - 1));
+        }
+        assert!(port.read_frame().is_err());
+    }
+
+    #[test]
+    fn test_echo_rule() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_echo_rule(b"ID?\r", b"DEV#");
+        port.write(b"ID?\r").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ID?\r");
+        assert_eq!(port.read_frame().unwrap(), b"DEV#");
+
+        port.write(b"ID?").unwrap();
+        assert!(port.read_frame().is_err());
+    }
+
+    #[test]
+    fn test_read_misalignment() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_read_misalignment(1);
+        port.add_response(b"abc#def#");
+        let mut reads = Vec::new();
+        while let Ok(n) = port.read(read_buf.as_mut_slice()) {
+            reads.push(read_buf[..n].to_vec());
+        }
+        assert_eq!(
+            reads,
+            [
+                b"abc".to_vec(),
+                b"#".to_vec(),
+                b"def".to_vec(),
+                b"#".to_vec()
+            ]
+        );
+
+        port.set_read_granularity(3);
+        port.add_response(b"abc#def#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"ab");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"c#d");
+    }
+
+    #[test]
+    fn test_pending_results() {
+        let mut port: MockableSerial = b"abcde#".to_vec().into();
+        port.fail_after_reads(2, ErrorKind::BrokenPipe);
+
+        let mut read_buf = [0; 1];
+        port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(
+            port.pending_results(),
+            [(true, ErrorKind::Other), (false, ErrorKind::BrokenPipe)]
+        );
+
+        port.clear_results();
+        assert!(port.pending_results().is_empty());
+        port.read(read_buf.as_mut_slice()).unwrap();
+        port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(read_buf[0], b'c');
+    }
+
+    #[test]
+    fn test_responsive_baud() {
+        let m = MockableSerialBuilder::new("/dev/null", 9600, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_responsive_baud(57600);
+        port.add_rule(b"ID?", b"mock#");
+        let mut found = Vec::new();
+        for baud in [9600, 19200, 57600, 115200] {
+            port.set_baud_rate(baud);
+            port.write(b"ID?").unwrap();
+            if let Ok(n) = port.read(read_buf.as_mut_slice()) {
+                assert_eq!(&read_buf[..n], b"mock#");
+                found.push(baud);
+            }
+        }
+        assert_eq!(found, [57600]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_scenario_json() {
+        let m = MockableSerialBuilder::new("/dev/ttyUSB0", 57600, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.add_response(b"boot#");
+        port.add_rule(b"ID?", b"mock#");
+        port.set_firmware_version("1.0");
+        port.add_rule_for_version("2.0", b"NEW", b"yes#");
+        port.add_rule_with_delay(b"SLOW", b"slow#", Duration::from_secs(60));
+        port.add_prefix_rule(b"P", b"p#");
+        port.add_echo_rule(b"E", b"e#");
+        port.add_cycling_rule(b"C", vec![b"c1#".to_vec(), b"c2#".to_vec()]);
+        port.add_random_choice_rule(b"R", vec![b"r#".to_vec()], 7);
+        let json = port.to_scenario_json();
+
+        let mut copy = MockableSerial::from_scenario_json(&json).unwrap();
+        assert_eq!(copy.to_scenario_json(), json);
+        assert_eq!(copy.name(), port.name());
+        assert_eq!(copy.effective_baud(), 57600);
+
+        let n = copy.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"boot#");
+        copy.write(b"ID?").unwrap();
+        let n = copy.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"mock#");
+
+        // The firmware version is a setting that is not exported
+        copy.set_firmware_version("1.0");
+        copy.write(b"NEW").unwrap();
+        copy.write(b"SLOW").unwrap();
+        assert_eq!(copy.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+        copy.set_firmware_version("2.0");
+        copy.write(b"NEW").unwrap();
+        assert_eq!(copy.read_frame().unwrap(), b"yes#");
+
+        assert!(MockableSerial::from_scenario_json("{}").is_err());
+    }
+
+    #[test]
+    fn test_persistent_config() {
+        let m = MockableSerialBuilder::new("/dev/null", 9600, b'#', 8, None);
+        let config = SerialConfig {
+            baud: 115200,
+            data_bits: 7,
+            parity: crate::Parity::Even,
+            stop_bits: 2,
+        };
+
+        let mut port = m.open_native();
+        port.add_reset_trigger(b"RST");
+        port.configure(config);
+        port.write(b"RST").unwrap();
+        assert_eq!(port.config(), config);
+
+        let mut port = m.open_native();
+        let factory = port.config();
+        port.add_reset_trigger(b"RST");
+        port.set_persistent_config(false);
+        port.configure(config);
+        assert_eq!(port.effective_baud(), 115200);
+        port.write(b"RST").unwrap();
+        assert_eq!(port.config(), factory);
+        assert_eq!(port.effective_baud(), 9600);
+    }
+
+    #[test]
+    fn test_write_reassembly() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_write_reassembly(b'\r');
+        port.add_rule(b"ID?\r", b"mock#");
+        port.write(b"ID").unwrap();
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+        port.write(b"?\r").unwrap();
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"mock#");
+    }
+
+    #[test]
+    fn test_fail_reads_with() {
+        let mut port: MockableSerial = b"abc#".to_vec().into();
+        let mut read_buf = [0; 8];
+
+        port.fail_reads_with(ErrorKind::TimedOut, "sensor 3 went quiet");
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(err.to_string().contains("sensor 3 went quiet"));
+    }
+
+    #[test]
+    fn test_bus_address() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_bus_address(0x02);
+        port.add_addressed_response(0x01, b"one#");
+        port.add_addressed_response(0x02, b"two#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"two#");
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+
+        port.add_rule(b"\x01ID?", b"one#");
+        port.add_rule(b"\x02ID?", b"two#");
+        port.write(b"\x01ID?").unwrap();
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+        port.write(b"\x02ID?").unwrap();
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"two#");
+    }
+
+    #[test]
+    fn test_interaction_sequence() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.enable_recording();
+        port.add_rule(b"ID?", b"mock#");
+        port.write(b"ID?").unwrap();
+        port.read(read_buf.as_mut_slice()).unwrap();
+        port.write(b"ID?").unwrap();
+        port.assert_interaction_sequence(&[
+            InteractionKind::Write,
+            InteractionKind::Read,
+            InteractionKind::Write,
+        ]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "interaction sequence differs: expected [Write, Read, Write], got [Write, Write]"
+    )]
+    fn test_interaction_sequence_missing_read() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.enable_recording();
+        port.write(b"ID?").unwrap();
+        port.write(b"ID?").unwrap();
+        port.assert_interaction_sequence(&[
+            InteractionKind::Write,
+            InteractionKind::Read,
+            InteractionKind::Write,
+        ]);
+    }
+
+    #[test]
+    fn test_random_choice_rule() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_random_choice_rule(
+            b"MODE?",
+            vec![b"A#".to_vec(), b"B#".to_vec(), b"C#".to_vec()],
+            1,
+        );
+        for expected in [b"C#", b"B#", b"A#", b"C#", b"A#", b"C#"] {
+            port.write(b"MODE?").unwrap();
+            assert_eq!(port.read_frame().unwrap(), expected);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_write_rate() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_clock(crate::ManualClock::new());
+
+        port.set_write_rate(1000);
+        let start = port.uptime();
+        port.write(&[0x55; 60]).unwrap();
+        port.write(&[0x55; 40]).unwrap();
+        assert!(port.uptime() - start >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_was_written() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.write(b"AB").unwrap();
+        port.write(b"CD").unwrap();
+        assert!(port.was_written(b"BC"));
+        assert!(port.was_written(b"ABCD"));
+        assert!(!port.was_written(b"AC"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_freeze_time() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.freeze_time();
+        port.add_delayed_response(b"ok#", Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+
+        port.advance_time(Duration::from_millis(19));
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+        port.advance_time(Duration::from_millis(1));
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+
+        port.unfreeze_time();
+        port.add_delayed_response(b"ok#", Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_split_baud() {
+        let m = MockableSerialBuilder::new("/dev/null", 9600, b'#', 96, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 96];
+        port.set_clock(crate::ManualClock::new());
+        port.set_timing_simulation(true);
+
+        port.set_rx_baud(19200);
+        assert_eq!(port.tx_baud(), 9600);
+        port.write(&[0x55; 96]).unwrap();
+        let start = port.uptime();
+        port.flush().unwrap();
+        assert_eq!(port.uptime() - start, Duration::from_millis(100));
+
+        let mut frame = [0x55; 96];
+        frame[95] = b'#';
+        port.add_response(&frame);
+        let start = port.uptime();
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 96);
+        assert_eq!(port.uptime() - start, Duration::from_millis(50));
+
+        port.set_tx_baud(4800);
+        port.write(&[0x55; 96]).unwrap();
+        let start = port.uptime();
+        port.flush().unwrap();
+        assert_eq!(port.uptime() - start, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_unknown_command_response() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_rule(b"ID?", b"mock#");
+        port.set_unknown_command_response(b"ERR#");
+        port.write(b"ID?").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"mock#");
+        port.write(b"FOO").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ERR#");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_recording_for() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_clock(clock.clone());
+
+        port.enable_recording_for(Duration::from_secs(1));
+        port.write(b"one").unwrap();
+        clock.advance(Duration::from_secs(1));
+        port.write(b"two").unwrap();
+        assert_eq!(port.recording(), [Interaction::Write(b"one".to_vec())]);
+    }
+
+    #[test]
+    fn test_startup_garbage() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 16, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 16];
+
+        port.add_response(b"ok#");
+        port.set_startup_garbage(b"\x00#\xff\x17");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"\x00#\xff\x17");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"ok#");
+    }
+
+    #[test]
+    fn test_read_only() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.set_read_only(true);
+        let err = port.write(b"ID?").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(port.written_messages().is_empty());
+
+        port.set_read_only(false);
+        assert_eq!(port.write(b"ID?").unwrap(), 3);
     }
 
+    #[cfg(feature = "regex")]
     #[test]
-    fn test_add_response() {
-        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x35, 1, None);
+    fn test_assert_next_frame_matches() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 16, None);
         let mut port = m.open_native();
-        port.add_response(&[0x65, 0x65, 0x65]);
 
-        assert_eq!(port.response_queue.get(0).unwrap(), &vec![0x65, 0x65, 0x65]);
+        port.add_response(b"VOLT:3.3V#");
+        let frame = port.assert_next_frame_matches(r"VOLT:\d+\.\d+V#").unwrap();
+        assert_eq!(frame, b"VOLT:3.3V#");
+        assert!(port.assert_next_frame_matches("(").is_err());
     }
 
+    #[cfg(feature = "regex")]
     #[test]
-    fn test_read() {
-        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+    #[should_panic(expected = "frame \"VOLT:low#\" does not match")]
+    fn test_assert_next_frame_matches_mismatch() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 16, None);
         let mut port = m.open_native();
-        port.add_response(&[
-            0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x77, 0x6f, 0x72, 0x6c, 0x64, 0x23,
-        ]);
-        let final_buffer = read_resp(&mut port);
-        assert_eq!(std::str::from_utf8(&final_buffer).unwrap(), "hello world#");
+
+        port.add_response(b"VOLT:low#");
+        let _ = port.assert_next_frame_matches(r"VOLT:\d+\.\d+V#");
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn test_initial_response() {
-        let init_resp = VecDeque::from([vec![0x65, 0x65, 0x65], vec![0x64, 0x64, 0x64]]);
-        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x35, 1, Some(init_resp));
+    fn test_clock_drift() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_clock(clock.clone());
+
+        port.set_clock_drift(100);
+        clock.advance(Duration::from_secs(1000));
+        assert_eq!(port.uptime(), Duration::from_millis(1_000_100));
+
+        port.set_clock_drift(-100);
+        let start = port.uptime();
+        clock.advance(Duration::from_secs(1000));
+        assert_eq!(port.uptime() - start, Duration::from_millis(999_900));
+    }
+
+    #[test]
+    fn test_write_parser() {
+        #[derive(Debug, PartialEq)]
+        struct Set {
+            value: u32,
+        }
+
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.set_write_parser(|b: &[u8]| {
+            let text = core::str::from_utf8(b).ok()?;
+            let value = text.strip_prefix("SET ")?.strip_suffix('#')?.parse().ok()?;
+            Some(Set { value })
+        });
+        port.write(b"SET 5#").unwrap();
+        port.write(b"GET#").unwrap();
+        assert_eq!(port.parsed_writes::<Set>(), [&Set { value: 5 }]);
+    }
+
+    #[test]
+    fn test_total_byte_limit() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_total_byte_limit(8);
+        port.add_response(b"abc#");
+        port.write(b"ID?#").unwrap();
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 4);
+
+        let err = port.write(b"ID?#").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+        port.add_response(b"abc#");
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.set_shuffle(1);
+        for frame in [b"0#", b"1#", b"2#", b"3#", b"4#"] {
+            port.add_response(frame);
+        }
+        let expected = [b"0#", b"4#", b"1#", b"3#", b"2#"];
+        assert_eq!(port.queued_order(), expected);
+        for frame in expected {
+            assert_eq!(port.read_frame().unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn test_delimiters() {
+        let mut port: MockableSerial = vec![0x55, 0x03, 0x02, b'o', b'k', 0x03].into();
+        port.set_delimiters(0x02, 0x03);
+
+        assert_eq!(port.read_frame().unwrap(), b"ok");
+        assert_eq!(port.discarded_bytes(), 2);
+    }
+
+    #[test]
+    fn test_framing() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        assert_eq!(port.framing(), Framing::StopByte);
+        port.set_framing(Framing::Cobs);
+        assert_eq!(port.framing(), Framing::Cobs);
+        port.set_unframed();
+        assert_eq!(port.framing(), Framing::Raw);
+    }
+
+    #[test]
+    fn test_fatal_on_error() {
+        let mut port: MockableSerial = b"abcde#".to_vec().into();
+        let mut read_buf = [0; 1];
+
+        port.set_fatal_on_error(true);
+        port.fail_after_reads(1, ErrorKind::BrokenPipe);
+        port.read(read_buf.as_mut_slice()).unwrap();
+        for _ in 0..3 {
+            let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        }
+
+        port.reset();
+        port.add_response(b"ok#");
+        port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(read_buf[0], b'o');
+    }
+
+    #[test]
+    fn test_session_report() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.add_rule(b"ID?", b"mock#");
+        port.add_response(b"left#");
+        port.write(b"ID?").unwrap();
+        port.inject_framing_error();
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+        port.read(read_buf.as_mut_slice()).unwrap();
+
+        let report = port.session_report();
+        assert!(report.contains("reads: 1 (5 bytes)\n"));
+        assert!(report.contains("writes: 1 (3 bytes)\n"));
+        assert!(report.contains("injected errors: 1\n"));
+        assert!(report.contains("timeouts: 0\n"));
+        assert!(report.contains("queued frames: 1\n"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_min_write_spacing() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_clock(clock.clone());
+
+        port.set_min_write_spacing(Duration::from_millis(10));
+        port.write(b"one").unwrap();
+        let err = port.write(b"two").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        port.set_drop_fast_writes(true);
+        assert_eq!(port.write(b"two").unwrap(), 3);
+        assert_eq!(port.dropped_writes(), 2);
+        clock.advance(Duration::from_millis(10));
+        port.write(b"three").unwrap();
+        assert_eq!(
+            port.written_messages(),
+            [b"one".to_vec(), b"three".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_on_empty() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        let mut fired = 0;
+        port.on_empty(move |p| {
+            fired += 1;
+            if fired == 1 {
+                p.add_response(b"more#");
+            }
+        });
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"more#");
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+    }
+
+    #[test]
+    fn test_set_stop_byte() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.add_response(b"abc#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"abc#");
+
+        port.set_stop_byte(b'\n');
+        port.add_response(b"def\n");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"def\n");
+
+        // Switching mid-frame leaves that frame's terminator alone
+        port.add_response(b"xy\nz#");
+        port.read(&mut read_buf[..1]).unwrap();
+        port.set_stop_byte(b'#');
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"y\n");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"z#");
+    }
+
+    #[test]
+    fn test_assert_idle() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
         let port = m.open_native();
 
-        assert_eq!(port.response_queue.get(0).unwrap(), &vec![0x65, 0x65, 0x65]);
-        assert_eq!(port.response_queue.get(1).unwrap(), &vec![0x64, 0x64, 0x64]);
+        port.assert_idle();
     }
 
     #[test]
-    fn test_multiple_responses() {
-        let init_resp = VecDeque::from([
-            vec![0x74, 0x65, 0x73, 0x74, 0x31, 0x23],
-            vec![0x74, 0x65, 0x73, 0x74, 0x32, 0x23],
-        ]);
-        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, Some(init_resp));
+    #[should_panic(expected = "expected no I/O, got 0 reads and 1 writes")]
+    fn test_assert_idle_after_write() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
         let mut port = m.open_native();
 
-        let resp1 = read_resp(&mut port);
-        let resp2 = read_resp(&mut port);
+        port.write(b"ID?").unwrap();
+        port.assert_idle();
+    }
 
-        assert_eq!(std::str::from_utf8(&resp1).unwrap(), "test1#");
-        assert_eq!(std::str::from_utf8(&resp2).unwrap(), "test2#");
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_response_due_at() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.set_clock(clock.clone());
+
+        let start = crate::Clock::now(&clock);
+        port.add_response_due_at(b"two#", start + Duration::from_millis(20));
+        port.add_response_due_at(b"one#", start + Duration::from_millis(10));
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+
+        clock.advance(Duration::from_millis(10));
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"one#");
+        clock.advance(Duration::from_millis(9));
+        assert!(port.read(read_buf.as_mut_slice()).is_err());
+        clock.advance(Duration::from_millis(1));
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"two#");
+    }
+
+    #[test]
+    fn test_bit_reversal() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.set_bit_reversal(true);
+        port.add_response(b"\x01\x0f#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], [0x80, 0xf0, b'#'.reverse_bits()]);
+
+        port.set_bit_reversal(false);
+        port.add_response(b"\x01\x0f#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"\x01\x0f#");
+    }
+
+    #[test]
+    fn test_written_stream() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        for fragment in [&b"ID"[..], b"?", b"\r\n"] {
+            port.write(fragment).unwrap();
+        }
+        assert_eq!(port.written_stream(), b"ID?\r\n");
+        assert_eq!(port.take_written_stream(), b"ID?\r\n");
+        assert!(port.written_messages().is_empty());
+        assert!(port.take_written_stream().is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_keepalive() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.set_clock(clock.clone());
+
+        port.set_keepalive(Duration::from_secs(1));
+        clock.advance(Duration::from_millis(900));
+        port.write(b"PING").unwrap();
+        clock.advance(Duration::from_millis(900));
+        port.write(b"PING").unwrap();
+
+        clock.advance(Duration::from_millis(1100));
+        let err = port.write(b"PING").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+
+        port.reset();
+        port.write(b"PING").unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_split() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let (mut a, mut b) = m.open_native().split();
+        let mut read_buf = [0; 8];
+
+        a.write(b"one").unwrap();
+        b.write(b"two").unwrap();
+        assert_eq!(a.shared_writes(), [b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(b.shared_writes(), a.shared_writes());
+        assert_eq!(a.written_messages(), [b"one".to_vec()]);
+
+        a.add_response(b"a#");
+        b.add_response(b"b#");
+        let n = a.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"a#");
+        assert!(a.read(read_buf.as_mut_slice()).is_err());
+        let n = b.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"b#");
+    }
+
+    #[test]
+    fn test_encoded_response() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_encoded_response(&[0xde, 0xad, 0x01], Encoding::Hex);
+        let frame = port.read_frame().unwrap();
+        assert_eq!(frame, b"dead01#");
+        assert_eq!(
+            decode_frame(&frame, Encoding::Hex).unwrap(),
+            [0xde, 0xad, 0x01]
+        );
+
+        for payload in [&b"hi"[..], b"hey", b"hello", b""] {
+            port.add_encoded_response(payload, Encoding::Base64);
+            let frame = port.read_frame().unwrap();
+            assert_eq!(decode_frame(&frame, Encoding::Base64).unwrap(), payload);
+        }
+        port.add_encoded_response(b"hello", Encoding::Base64);
+        assert_eq!(port.read_frame().unwrap(), b"aGVsbG8=#");
+
+        assert!(decode_frame(b"abc#", Encoding::Hex).is_err());
+        assert!(decode_frame(b"a=bc#", Encoding::Base64).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_split_delayed_response() {
+        let clock = crate::ManualClock::new();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.set_clock(clock.clone());
+
+        port.add_split_delayed_response(b"TEM", b"P=21#", Duration::from_millis(50));
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"TEM");
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        clock.advance(Duration::from_millis(50));
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"P=21#");
+    }
+
+    #[test]
+    fn test_misuse_error() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        let err = port.try_add_response(b"").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(port.take_internal_error().is_some());
+        port.add_response(b"");
+        assert_eq!(
+            port.take_internal_error().unwrap(),
+            "add_response called with an empty response"
+        );
+        assert!(port.queued_order().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "add_response called with an empty response")]
+    fn test_misuse_panic() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.set_strict_misuse(true);
+        port.add_response(b"");
+    }
+
+    #[test]
+    fn test_pattern_stream() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_pattern_stream(vec![1, 2, 3, 4], 1000);
+
+        let mut received = Vec::new();
+        let mut read_buf = [0; 64];
+        loop {
+            let n = port.read(read_buf.as_mut_slice()).unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&read_buf[..n]);
+        }
+        assert_eq!(received.len(), 1000);
+        assert!(received.chunks(4).all(|c| c == [1, 2, 3, 4]));
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_require_flush_between() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.require_flush_between(true);
+        port.add_response(b"ok#");
+        port.add_response(b"ok#");
+        let mut read_buf = [0; 8];
+
+        port.write(b"cmd#").unwrap();
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(port.flush_violations(), 1);
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+
+        port.write(b"cmd#").unwrap();
+        port.flush().unwrap();
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+        assert_eq!(port.flush_violations(), 1);
+    }
+
+    #[test]
+    fn test_line_ack() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_line_ack(b"ACK#");
+        port.set_strict_line_ack(true);
+
+        port.write(b"line one#").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ACK#");
+        port.write(b"line two#").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ACK#");
+        assert_eq!(
+            port.written_messages(),
+            vec![b"line one#".to_vec(), b"line two#".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_line_ack_strict_violation() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_line_ack(b"ACK#");
+        port.set_strict_line_ack(true);
+
+        port.write(b"line one#").unwrap();
+        let err = port.write(b"line two#").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert_eq!(port.written_messages(), vec![b"line one#".to_vec()]);
+
+        port.set_strict_line_ack(false);
+        port.write(b"line two#").unwrap();
+        assert_eq!(port.queued_order().len(), 2);
+    }
+
+    #[test]
+    fn test_terminator_stays_with_its_frame() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_response(b"x\n");
+        port.add_response_with_terminator(b"x", b'\n');
+        port.add_response(b"y#");
+
+        assert_eq!(port.read_frame().unwrap(), b"x\nx\n");
+        assert_eq!(port.read_frame().unwrap(), b"y#");
+    }
+
+    #[test]
+    fn test_truncated_response_drops_after_its_frame() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_truncated_response(b"ab");
+        port.inject_unsolicited(b"zzzz#");
+
+        assert_eq!(port.read_frame().unwrap(), b"zzzz#");
+        assert_eq!(port.read_frame().unwrap(), b"ab");
+        assert_eq!(
+            port.read_frame().unwrap_err().kind(),
+            ErrorKind::NotConnected
+        );
+    }
+
+    #[test]
+    fn test_reset_clears_pending_drop() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_reset_trigger(b"RST#");
+        port.add_truncated_response(b"ab");
+        let mut read_buf = [0; 1];
+        port.read(read_buf.as_mut_slice()).unwrap();
+
+        port.write(b"RST#").unwrap();
+        port.add_response(b"hello#");
+        assert_eq!(port.read_frame().unwrap(), b"hello#");
+        port.write(b"ping#").unwrap();
+    }
+
+    #[test]
+    fn test_unsolicited_comes_next_in_lifo() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.set_order(QueueOrder::Lifo);
+        port.add_response(b"a#");
+        port.add_response(b"b#");
+        port.inject_unsolicited(b"u#");
+
+        assert_eq!(port.read_frame().unwrap(), b"u#");
+        assert_eq!(port.read_frame().unwrap(), b"b#");
+        assert_eq!(port.read_frame().unwrap(), b"a#");
+    }
+
+    #[test]
+    fn test_startup_garbage_is_one_chunk() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 16, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 16];
+
+        port.add_response(b"ok#");
+        port.set_startup_garbage(b"\x17\x00#\xff\x17");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"\x17\x00#\xff\x17");
+        assert_eq!(port.read_frame().unwrap(), b"ok#");
+
+        port.set_startup_garbage(b"#\x01#");
+        assert!(port.validate_frames().is_ok());
+        assert_eq!(port.read_frame().unwrap(), b"#\x01#");
+    }
+
+    #[test]
+    fn test_version_gate_stays_with_its_rule() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.add_rule(b"A", b"1#");
+        port.add_rule_for_version("2.0", b"A", b"1#");
+        port.write(b"A").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"1#");
+    }
+
+    #[test]
+    fn test_fail_after_reads_skips_empty_reads() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.fail_after_reads(1, ErrorKind::BrokenPipe);
+        let mut read_buf = [0; 8];
+
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        port.add_response(b"ok#");
+        port.add_response(b"ok#");
+        assert_eq!(port.read(read_buf.as_mut_slice()).unwrap(), 3);
+        let err = port.read(read_buf.as_mut_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_addressed_response_checked_on_delivery() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_addressed_response(1, b"one#");
+        port.add_addressed_response(2, b"two#");
+        port.set_bus_address(2);
+
+        assert_eq!(port.queued_order(), vec![b"two#".to_vec()]);
+        assert_eq!(port.read_frame().unwrap(), b"two#");
+        assert_eq!(port.read_frame().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_read_sleeps_until_due() {
+        tokio::time::pause();
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        port.add_delayed_response(b"ok#", Duration::from_millis(200));
+
+        // A paused runtime only moves its clock on once every task idles
+        let mut read_buf = [0; 8];
+        let mut buf = tokio::io::ReadBuf::new(&mut read_buf);
+        core::future::poll_fn(|cx| {
+            tokio::io::AsyncRead::poll_read(core::pin::Pin::new(&mut port), cx, &mut buf)
+        })
+        .await
+        .unwrap();
+        assert_eq!(buf.filled(), b"ok#");
+    }
+
+    #[test]
+    fn test_echo_rule_trimmed() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.set_rule_trim(b"\r");
+        port.add_echo_rule(b"ID?", b"DEV#");
+        port.write(b"ID?\r").unwrap();
+        assert_eq!(port.read_frame().unwrap(), b"ID?\r");
+        assert_eq!(port.read_frame().unwrap(), b"DEV#");
+    }
+
+    #[test]
+    fn test_frames_in_one_response() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+
+        port.add_response(b"ab#cd#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"ab#");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"cd#");
+
+        port.add_response(b"ef#gh#");
+        assert_eq!(port.read_frame().unwrap(), b"ef#");
+        assert_eq!(port.read_frame().unwrap(), b"gh#");
+    }
+
+    #[test]
+    fn test_framing_restores_stop_byte() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+
+        port.set_framing(Framing::Cobs);
+        port.set_framing(Framing::Slip);
+        assert_eq!(port.stop_byte(), crate::SLIP_END);
+        port.set_framing(Framing::StopByte);
+        assert_eq!(port.stop_byte(), b'#');
+        port.add_response(b"ok#");
+        assert_eq!(port.read_frame().unwrap(), b"ok#");
+    }
+
+    #[test]
+    fn test_delimiters_keep_end_and_clear() {
+        let mut port: MockableSerial = vec![0x55, 0x02, b'o', b'k', 0x03].into();
+        let stop = port.stop_byte();
+        port.set_delimiters(0x02, 0x03);
+        port.set_keep_end_delimiter(true);
+        assert_eq!(port.read_frame().unwrap(), b"ok\x03");
+
+        port.clear_delimiters();
+        assert_eq!(port.stop_byte(), stop);
+        port.add_response(&[0x02, b'o', b'k', stop]);
+        assert_eq!(port.read_frame().unwrap(), [0x02, b'o', b'k', stop]);
+    }
+
+    #[test]
+    fn test_split_stop_sequence() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, b'#', 8, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 8];
+        port.set_stop_sequence(b"\r\n");
+
+        // A bare \n is data, the \r\n may span two responses
+        port.add_response(b"A\nB\r");
+        let err = port.read_frame().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        port.add_response(b"\n");
+        assert_eq!(port.read_frame().unwrap(), b"A\nB\r\n");
+
+        // The \r and the \n come out of separate reads
+        port.set_chunk_sizes(vec![3, 1]);
+        port.add_response(b"OK\r\n");
+        port.add_response_after_read(2, b"NEXT\r\n");
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"OK\r");
+        assert_eq!(port.queued_order(), vec![b"\n".to_vec()]);
+        let n = port.read(read_buf.as_mut_slice()).unwrap();
+        assert_eq!(&read_buf[..n], b"\n");
+        assert_eq!(port.read_frame().unwrap(), b"NEXT\r\n");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_hang() {
+        let m = MockableSerialBuilder::new("/dev/null", 115200, 0x23, 1, None);
+        let mut port = m.open_native();
+        let mut read_buf = [0; 1];
+        port.add_response(&[0x6f, 0x6b, 0x23]);
+
+        port.set_hang(true);
+        for _ in 0..2 {
+            let start = std::time::Instant::now();
+            let err = port
+                .read_timeout(read_buf.as_mut_slice(), Duration::from_millis(20))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        }
+
+        port.set_hang(false);
+        let n = port
+            .read_timeout(read_buf.as_mut_slice(), Duration::from_millis(20))
+            .unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(read_buf[0], 0x6f);
     }
 }